@@ -15,6 +15,12 @@ in {
       description = "The name of the S3 bucket to serve.";
     };
 
+    prefix = lib.mkOption {
+      type = lib.types.str;
+      default = "";
+      description = "Only serve objects below this key prefix in the bucket.";
+    };
+
     baseUrl = lib.mkOption {
       type = lib.types.str;
       default = "http://localhost:3000";
@@ -84,6 +90,7 @@ in {
           ${lib.getExe cfg.package} \
             --bucket ${cfg.bucket}  \
             --base-url ${cfg.baseUrl} \
+            ${lib.optionalString (cfg.prefix != "") "--prefix ${cfg.prefix}"} \
             ${lib.optionalString (cfg.jwtPublicKey != null)
               "--jwt-pem \${CREDENTIALS_DIRECTORY}/pem"}
         '';
@@ -1,8 +1,12 @@
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
-use s3_nix_channel::persistent::Client;
+use s3_nix_channel::{
+    persistent::{ChannelEntry, Client, PublishInfo},
+    storage::S3Options,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 #[derive(Subcommand, Debug)]
 enum Commands {
@@ -21,6 +25,23 @@ enum Commands {
 
         /// The file extension for the channel.
         extension: String,
+
+        /// File extensions of additional files that can be published
+        /// together with the main one.
+        extra_extensions: Vec<String>,
+    },
+    /// Allow additional files with these extensions in future releases
+    /// of a channel.
+    AddExtensions {
+        /// The S3 bucket to upload the content to.
+        bucket: String,
+
+        /// The channel to change.
+        channel: String,
+
+        /// The additional file extensions, e.g. ".iso".
+        #[arg(required = true)]
+        extensions: Vec<String>,
     },
     /// Show the channel details.
     ShowChannel {
@@ -30,6 +51,25 @@ enum Commands {
         /// The channel to publish for.
         channel: String,
     },
+    /// Point an alias to a channel.
+    SetAlias {
+        /// The S3 bucket to upload the content to.
+        bucket: String,
+
+        /// The alternative name, e.g. "stable".
+        alias: String,
+
+        /// The channel the alias points to.
+        channel: String,
+    },
+    /// Remove an alias.
+    RemoveAlias {
+        /// The S3 bucket to upload the content to.
+        bucket: String,
+
+        /// The alias to remove.
+        alias: String,
+    },
     Publish {
         /// The S3 bucket to upload the content to.
         bucket: String,
@@ -37,15 +77,112 @@ enum Commands {
         /// The channel to publish for.
         channel: String,
 
-        /// The file to upload.
-        file: PathBuf,
+        /// The files to upload. One of them needs the main file
+        /// extension of the channel, the others extra extensions.
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Who publishes the file. Defaults to the current user.
+        #[arg(long)]
+        uploader: Option<String>,
+
+        #[command(flatten)]
+        source: SourceArgs,
     },
 }
 
+/// Where the published file comes from. Nix records this in flake lock
+/// files.
+#[derive(clap::Args, Debug)]
+struct SourceArgs {
+    /// Detect the git revision, revision count and last modification
+    /// time from the HEAD of this git repository.
+    #[arg(long)]
+    git_repo: Option<PathBuf>,
+
+    /// The git revision the file was built from.
+    #[arg(long)]
+    git_revision: Option<String>,
+
+    /// The number of commits up to the git revision.
+    #[arg(long)]
+    revision_count: Option<u64>,
+
+    /// The commit time of the git revision as Unix timestamp.
+    #[arg(long)]
+    last_modified: Option<u64>,
+}
+
+/// Run git in the given repository and return its trimmed output.
+fn git(repo: &Path, args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()
+        .context("Failed to run git")?;
+
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8(output.stdout)
+        .context("git output is not valid UTF-8")?
+        .trim()
+        .to_owned())
+}
+
+impl SourceArgs {
+    /// Combine the explicitly given attributes with the ones we detect.
+    /// Explicit values win.
+    fn into_publish_info(self, uploader: Option<String>) -> Result<PublishInfo> {
+        let mut info = PublishInfo {
+            uploader,
+            ..Default::default()
+        };
+
+        if let Some(repo) = &self.git_repo {
+            info.git_revision = Some(git(repo, &["rev-parse", "HEAD"])?);
+            info.revision_count = Some(
+                git(repo, &["rev-list", "--count", "HEAD"])?
+                    .parse()
+                    .context("Invalid revision count")?,
+            );
+            info.last_modified = Some(
+                git(repo, &["log", "-1", "--format=%ct", "HEAD"])?
+                    .parse()
+                    .context("Invalid commit time")?,
+            );
+        }
+
+        info.git_revision = self.git_revision.or(info.git_revision);
+        info.revision_count = self.revision_count.or(info.revision_count);
+        info.last_modified = self.last_modified.or(info.last_modified);
+
+        Ok(info)
+    }
+}
+
 /// A program to serve a S3 bucket via the Nix Lockable Tarball Protocol.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Interpret the bucket argument as a local directory instead of an
+    /// S3 bucket.
+    #[arg(long, global = true)]
+    local: bool,
+
+    /// Place all metadata and tarballs below this key prefix.
+    #[arg(long, global = true, default_value = "")]
+    prefix: String,
+
+    #[command(flatten)]
+    s3: S3Options,
+
     #[command(subcommand)]
     commands: Commands,
 }
@@ -58,12 +195,26 @@ impl Args {
                 bucket,
                 channel: _,
                 extension: _,
+                extra_extensions: _,
+            }
+            | Commands::AddExtensions {
+                bucket,
+                channel: _,
+                extensions: _,
             }
             | Commands::ShowChannel { bucket, channel: _ }
+            | Commands::SetAlias {
+                bucket,
+                alias: _,
+                channel: _,
+            }
+            | Commands::RemoveAlias { bucket, alias: _ }
             | Commands::Publish {
                 bucket,
                 channel: _,
-                file: _,
+                files: _,
+                uploader: _,
+                source: _,
             } => bucket,
         }
     }
@@ -72,51 +223,144 @@ impl Args {
 async fn list_channels(s3_client: &Client) -> Result<()> {
     let config = s3_client.load_channels_config().await?;
 
+    config.channels().for_each(|(name, cfg)| {
+        println!(
+            "{name} ({})",
+            cfg.file_extensions().collect::<Vec<_>>().join(", ")
+        )
+    });
     config
-        .channels()
-        .for_each(|(name, cfg)| println!("{name} ({})", cfg.file_extension));
+        .aliases()
+        .for_each(|(alias, channel)| println!("{alias} -> {channel}"));
 
     Ok(())
 }
 
-async fn add_channel(s3_client: &Client, channel: &str, extension: &str) -> Result<()> {
+async fn add_channel(
+    s3_client: &Client,
+    channel: &str,
+    extension: &str,
+    extra_extensions: &[String],
+) -> Result<()> {
     s3_client
-        .add_channel(channel, extension)
+        .add_channel(channel, extension, extra_extensions)
         .await
         .context("Failed create channel")?;
 
     Ok(())
 }
 
+async fn add_extensions(s3_client: &Client, channel: &str, extensions: &[String]) -> Result<()> {
+    s3_client
+        .add_file_extensions(channel, extensions)
+        .await
+        .context("Failed to add file extensions")?;
+
+    Ok(())
+}
+
+async fn set_alias(s3_client: &Client, alias: &str, channel: Option<&str>) -> Result<()> {
+    s3_client
+        .set_alias(alias, channel)
+        .await
+        .context("Failed to update alias")?;
+
+    Ok(())
+}
+
+/// Print an element of a channel with all metadata we know.
+fn print_entry(entry: &ChannelEntry) {
+    println!("{}", entry.name);
+
+    if let Some(published) = entry.published.and_then(|p| p.format(&Rfc3339).ok()) {
+        println!("  Published:    {published}");
+    }
+    if let Some(uploader) = &entry.uploader {
+        println!("  Uploader:     {uploader}");
+    }
+    if let Some(size) = entry.size {
+        println!("  Size:         {size} bytes");
+    }
+    if let Some(sha256) = &entry.sha256 {
+        println!("  SHA-256:      {sha256}");
+    }
+    for (file_extension, artifact) in &entry.artifacts {
+        println!("  Artifact:     {}{file_extension}", entry.name);
+        if let Some(size) = artifact.size {
+            println!("    Size:       {size} bytes");
+        }
+        if let Some(sha256) = &artifact.sha256 {
+            println!("    SHA-256:    {sha256}");
+        }
+    }
+    if let Some(git_revision) = &entry.git_revision {
+        println!("  Git revision: {git_revision}");
+    }
+    if let Some(revision_count) = entry.revision_count {
+        println!("  Rev count:    {revision_count}");
+    }
+    if let Some(last_modified) = entry
+        .last_modified
+        .and_then(|timestamp| i64::try_from(timestamp).ok())
+        .and_then(|timestamp| OffsetDateTime::from_unix_timestamp(timestamp).ok())
+        .and_then(|timestamp| timestamp.format(&Rfc3339).ok())
+    {
+        println!("  Modified:     {last_modified}");
+    }
+}
+
 async fn show_channel(s3_client: &Client, channel: &str) -> Result<()> {
     let config = s3_client.load_channels_config().await?;
+    let channel_config = config.channel(channel).context("No such channel")?;
 
-    println!(
-        "Latest: {}",
-        config
-            .channel(channel)
-            .context("No such channel")?
-            .latest
-            .as_deref()
-            .unwrap_or("(nothing yet)")
-    );
+    print!("Latest: ");
+    match &channel_config.latest {
+        Some(entry) => print_entry(entry),
+        None => println!("(nothing yet)"),
+    }
+
+    if !channel_config.previous.is_empty() {
+        println!("Previous:");
+        channel_config.previous.iter().rev().for_each(print_entry);
+    }
 
     Ok(())
 }
 
-async fn publish(s3_client: &Client, channel: &str, file: &Path) -> Result<()> {
+async fn publish(
+    s3_client: &Client,
+    channel: &str,
+    files: &[PathBuf],
+    info: &PublishInfo,
+) -> Result<()> {
+    let files = files.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+
     s3_client
-        .update_channel(channel, file)
+        .update_channel(channel, &files, info)
         .await
         .context("Failed to update channel")?;
 
+    println!(
+        "Published {} to channel {channel}.",
+        files
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
     Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    let s3_client = Client::new_from_env(args.bucket()).await?;
+    let s3_client = if args.local {
+        Client::new_local(Path::new(args.bucket()))?
+    } else {
+        Client::new_s3(args.bucket(), args.s3.clone()).await?
+    }
+    .with_prefix(&args.prefix);
 
     tracing_subscriber::fmt()
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
@@ -130,12 +374,30 @@ async fn main() -> Result<()> {
             bucket: _,
             channel,
             extension,
-        } => add_channel(&s3_client, &channel, &extension).await?,
+            extra_extensions,
+        } => add_channel(&s3_client, &channel, &extension, &extra_extensions).await?,
+        Commands::AddExtensions {
+            bucket: _,
+            channel,
+            extensions,
+        } => add_extensions(&s3_client, &channel, &extensions).await?,
+        Commands::SetAlias {
+            bucket: _,
+            alias,
+            channel,
+        } => set_alias(&s3_client, &alias, Some(&channel)).await?,
+        Commands::RemoveAlias { bucket: _, alias } => set_alias(&s3_client, &alias, None).await?,
         Commands::Publish {
             bucket: _,
             channel,
-            file,
-        } => publish(&s3_client, &channel, &file).await?,
+            files,
+            uploader,
+            source,
+        } => {
+            let info = source.into_publish_info(uploader.or_else(|| std::env::var("USER").ok()))?;
+
+            publish(&s3_client, &channel, &files, &info).await?
+        }
     }
 
     Ok(())
@@ -0,0 +1,111 @@
+//! A read-only JSON view of the channels we serve.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use serde::Serialize;
+
+use s3_nix_channel::{
+    error::RequestError,
+    persistent::{ChannelConfig, ChannelEntry},
+};
+
+use crate::{auth::Access, Config};
+
+/// A channel as we show it to API users.
+#[derive(Serialize, Debug)]
+pub struct ChannelInfo {
+    pub name: String,
+
+    /// Where the latest element of the channel can be downloaded.
+    pub url: String,
+
+    pub file_extension: String,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_file_extensions: Vec<String>,
+
+    pub latest: Option<ChannelEntry>,
+
+    /// All elements of the channel, newest first. The n-th element is
+    /// available as `{channel}~{n}{file_extension}`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<Vec<ChannelEntry>>,
+}
+
+impl ChannelInfo {
+    pub fn new(
+        base_url: &str,
+        name: &str,
+        channel_config: &ChannelConfig,
+        with_history: bool,
+    ) -> ChannelInfo {
+        ChannelInfo {
+            name: name.to_owned(),
+            url: format!("{base_url}/channel/{name}{}", channel_config.file_extension),
+            file_extension: channel_config.file_extension.clone(),
+            extra_file_extensions: channel_config.extra_file_extensions.clone(),
+            latest: channel_config.latest.clone(),
+            history: with_history.then(|| channel_config.history().cloned().collect()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChannelList {
+    pub channels: Vec<ChannelInfo>,
+
+    /// A mapping from alias to channel name.
+    pub aliases: BTreeMap<String, String>,
+}
+
+/// List all accessible channels with their latest element.
+pub async fn handle_channels(
+    State(config): State<Arc<Config>>,
+    access: Access,
+) -> Json<ChannelList> {
+    let channels_config = config.channels.load();
+    let channels = channels_config
+        .channels()
+        .filter(|(name, _)| access.allows(name))
+        .map(|(name, channel_config)| {
+            ChannelInfo::new(&config.base_url, name, channel_config, false)
+        })
+        .collect();
+
+    let aliases = channels_config
+        .aliases()
+        .filter(|(_, channel_name)| access.allows(channel_name))
+        .map(|(alias, channel_name)| (alias.to_owned(), channel_name.to_owned()))
+        .collect();
+
+    Json(ChannelList { channels, aliases })
+}
+
+/// Show a single channel with its whole history. Aliases are resolved.
+pub async fn handle_channel_info(
+    Path(name): Path<String>,
+    State(config): State<Arc<Config>>,
+    access: Access,
+) -> Result<Json<ChannelInfo>, RequestError> {
+    let channels_config = config.channels.load();
+    let channel_name = channels_config.resolve_alias(&name);
+    let channel_config =
+        channels_config
+            .channel(channel_name)
+            .ok_or_else(|| RequestError::NoSuchChannel {
+                file_name: name.clone(),
+            })?;
+
+    access.check(channel_name)?;
+
+    Ok(Json(ChannelInfo::new(
+        &config.base_url,
+        channel_name,
+        &channel_config,
+        true,
+    )))
+}
@@ -0,0 +1,697 @@
+//! Authentication and authorization of requests via JWT.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+
+use axum::{
+    extract::{FromRequestParts, Query, Request, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap, StatusCode, Uri},
+    middleware::Next,
+    response::{self, IntoResponse},
+};
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey, Header, Validation,
+};
+use tracing::{debug, info, warn};
+
+use s3_nix_channel::error::RequestError;
+
+#[derive(Debug, serde::Deserialize)]
+struct Claims {
+    /// Glob patterns of the channels the token grants access to, e.g.
+    /// "nixos-*". Tokens without this claim grant access to all
+    /// channels.
+    #[serde(default)]
+    channels: Option<Vec<String>>,
+
+    /// All other claims. We only check whether required ones are there.
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+impl Claims {
+    fn has(&self, claim: &str) -> bool {
+        match claim {
+            "channels" => self.channels.is_some(),
+            _ => self.other.contains_key(claim),
+        }
+    }
+}
+
+/// Claims that jsonwebtoken can check for presence itself.
+const SPEC_CLAIMS: [&str; 5] = ["exp", "nbf", "aud", "iss", "sub"];
+
+/// What we expect from the claims of a token beyond a valid signature.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct ClaimOptions {
+    /// Only accept tokens with this issuer (`iss`). Can be given several
+    /// times to accept any of them.
+    #[arg(long = "jwt-issuer")]
+    pub issuers: Vec<String>,
+
+    /// Only accept tokens for this audience (`aud`). Can be given
+    /// several times to accept any of them.
+    #[arg(long = "jwt-audience")]
+    pub audiences: Vec<String>,
+
+    /// Reject tokens without this claim. `exp` is always required.
+    #[arg(long = "jwt-required-claim")]
+    pub required_claims: Vec<String>,
+
+    /// How many seconds of clock skew we tolerate when checking `exp`
+    /// and `nbf`.
+    #[arg(long = "jwt-leeway-seconds", default_value_t = 60)]
+    pub leeway_seconds: u64,
+}
+
+impl ClaimOptions {
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway_seconds;
+
+        if !self.issuers.is_empty() {
+            validation.set_issuer(&self.issuers);
+        }
+
+        // Without a configured audience, jsonwebtoken would reject all
+        // tokens with an `aud` claim.
+        validation.validate_aud = !self.audiences.is_empty();
+        if validation.validate_aud {
+            validation.set_audience(&self.audiences);
+        }
+
+        // jsonwebtoken only checks `iss` and `aud` if they are present.
+        let spec_claims = std::iter::once("exp")
+            .chain((!self.issuers.is_empty()).then_some("iss"))
+            .chain((!self.audiences.is_empty()).then_some("aud"))
+            .chain(
+                self.required_claims
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|claim| SPEC_CLAIMS.contains(claim)),
+            )
+            .collect::<Vec<_>>();
+        validation.set_required_spec_claims(&spec_claims);
+
+        validation
+    }
+
+    /// Check the presence of required claims that jsonwebtoken doesn't
+    /// know about.
+    fn check_required(&self, claims: &Claims) -> Result<(), RequestError> {
+        let missing = self
+            .required_claims
+            .iter()
+            .find(|claim| !SPEC_CLAIMS.contains(&claim.as_str()) && !claims.has(claim));
+
+        match missing {
+            Some(claim) => Err(RequestError::InvalidToken {
+                reason: format!("Missing required claim {claim}"),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Everything the authentication middleware needs.
+pub struct AuthState {
+    /// The keys we accept. They can change at runtime.
+    pub keys: Arc<ArcSwap<JwtKeys>>,
+
+    pub claim_options: ClaimOptions,
+
+    /// The query parameter that can carry a token, if any.
+    pub query_parameter: Option<String>,
+}
+
+/// Which channels a request may access. Handlers extract this from the
+/// request. Without authentication, everything is accessible.
+#[derive(Debug, Clone, Default)]
+pub struct Access {
+    /// Glob patterns of accessible channels or `None` for all of them.
+    channels: Option<Vec<String>>,
+}
+
+impl Access {
+    pub fn allows(&self, channel_name: &str) -> bool {
+        self.channels.as_ref().is_none_or(|patterns| {
+            patterns
+                .iter()
+                .any(|pattern| glob_match(pattern, channel_name))
+        })
+    }
+
+    /// Fail with 403, if the channel is not accessible.
+    pub fn check(&self, channel_name: &str) -> Result<(), RequestError> {
+        if self.allows(channel_name) {
+            Ok(())
+        } else {
+            Err(RequestError::AccessDenied {
+                channel_name: channel_name.to_owned(),
+            })
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Access {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<Access>()
+            .cloned()
+            .unwrap_or_default())
+    }
+}
+
+/// Match a name against a glob pattern. `*` matches any number of
+/// characters and `?` exactly one.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    // Where we continue, if matching after the last star fails: the
+    // position after the star and the name position it consumed up to.
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the star consume one more character.
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The signature algorithms we accept.
+const SUPPORTED_ALGORITHMS: [Algorithm; 3] = [Algorithm::RS256, Algorithm::ES256, Algorithm::EdDSA];
+
+/// A public key that tokens can be signed with.
+#[derive(Clone)]
+pub struct JwtKey {
+    /// The key ID that tokens carry in their header to select this key.
+    /// Tokens without a key ID use the key without one.
+    kid: Option<String>,
+    algorithm: Algorithm,
+    decoding_key: DecodingKey,
+}
+
+impl JwtKey {
+    /// Load a public key in PEM format.
+    pub fn from_pem(
+        kid: Option<String>,
+        algorithm: Algorithm,
+        pem_data: &[u8],
+    ) -> Result<JwtKey, jsonwebtoken::errors::Error> {
+        let decoding_key = match algorithm {
+            Algorithm::ES256 => DecodingKey::from_ec_pem(pem_data)?,
+            Algorithm::EdDSA => DecodingKey::from_ed_pem(pem_data)?,
+            _ => DecodingKey::from_rsa_pem(pem_data)?,
+        };
+
+        Ok(JwtKey {
+            kid,
+            algorithm,
+            decoding_key,
+        })
+    }
+
+    /// Use a key from a JSON Web Key Set. Keys without an algorithm use
+    /// the one that fits their key type.
+    fn from_jwk(jwk: &Jwk) -> Result<JwtKey, String> {
+        let algorithm = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+            (Some(key_algorithm), _) => Algorithm::from_str(&key_algorithm.to_string())
+                .map_err(|_| format!("Unsupported algorithm {key_algorithm}"))?,
+            (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+            (None, AlgorithmParameters::EllipticCurve(params))
+                if params.curve == EllipticCurve::P256 =>
+            {
+                Algorithm::ES256
+            }
+            (None, AlgorithmParameters::OctetKeyPair(params))
+                if params.curve == EllipticCurve::Ed25519 =>
+            {
+                Algorithm::EdDSA
+            }
+            (None, _) => return Err("Unsupported key type".to_owned()),
+        };
+
+        if !SUPPORTED_ALGORITHMS.contains(&algorithm) {
+            return Err(format!("Unsupported algorithm {algorithm:?}"));
+        }
+
+        Ok(JwtKey {
+            kid: jwk.common.key_id.clone(),
+            algorithm,
+            decoding_key: DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+/// Load all usable keys from a JSON Web Key Set. We skip keys we can't
+/// use, so one odd key doesn't lock everybody out.
+fn keys_from_jwks(jwks: &JwkSet) -> Vec<JwtKey> {
+    jwks.keys
+        .iter()
+        .filter_map(|jwk| {
+            JwtKey::from_jwk(jwk)
+                .inspect_err(|e| {
+                    warn!(
+                        "Ignoring key {:?} in JWKS: {e}",
+                        jwk.common.key_id.as_deref().unwrap_or_default()
+                    )
+                })
+                .ok()
+        })
+        .collect()
+}
+
+/// A public key as given on the command line: `KID:ALGORITHM:PEM-FILE`.
+#[derive(Debug, Clone)]
+pub struct JwtKeySpec {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub pem_file: PathBuf,
+}
+
+impl FromStr for JwtKeySpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(kid), Some(algorithm), Some(pem_file)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err("Expected KID:ALGORITHM:PEM-FILE".to_owned());
+        };
+
+        let algorithm = Algorithm::from_str(algorithm)
+            .ok()
+            .filter(|algorithm| SUPPORTED_ALGORITHMS.contains(algorithm))
+            .ok_or_else(|| {
+                format!("Unsupported algorithm {algorithm:?}. Use one of RS256, ES256 or EdDSA.")
+            })?;
+
+        if kid.is_empty() {
+            return Err("The key ID must not be empty".to_owned());
+        }
+
+        Ok(JwtKeySpec {
+            kid: kid.to_owned(),
+            algorithm,
+            pem_file: pem_file.into(),
+        })
+    }
+}
+
+/// All keys that we accept token signatures from.
+pub struct JwtKeys {
+    keys: Vec<JwtKey>,
+}
+
+/// Where our keys come from. Keys from a JWKS file can change while we
+/// are running.
+pub struct KeySources {
+    /// Keys given on the command line.
+    pub static_keys: Vec<JwtKey>,
+
+    /// A file with a JSON Web Key Set.
+    pub jwks_file: Option<PathBuf>,
+}
+
+impl KeySources {
+    pub fn is_empty(&self) -> bool {
+        self.static_keys.is_empty() && self.jwks_file.is_none()
+    }
+
+    /// Load the current set of keys.
+    pub fn load(&self) -> anyhow::Result<JwtKeys> {
+        let mut keys = self.static_keys.clone();
+
+        if let Some(jwks_file) = &self.jwks_file {
+            keys.extend(keys_from_jwks(&read_jwks(jwks_file)?));
+        }
+
+        Ok(JwtKeys::new(keys))
+    }
+}
+
+fn read_jwks(jwks_file: &Path) -> anyhow::Result<JwkSet> {
+    let data = std::fs::read(jwks_file)
+        .with_context(|| format!("Failed to read JWKS from {}", jwks_file.display()))?;
+
+    serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse JWKS from {}", jwks_file.display()))
+}
+
+impl JwtKeys {
+    pub fn new(keys: Vec<JwtKey>) -> JwtKeys {
+        JwtKeys { keys }
+    }
+
+    /// Pick the key that the token header asks for. Tokens with an
+    /// unknown key ID fall back to the key without ID, because identity
+    /// providers set `kid` even if we only know their key as PEM.
+    fn select(&self, header: &Header) -> Result<&JwtKey, RequestError> {
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.kid == header.kid)
+            .or_else(|| self.keys.iter().find(|key| key.kid.is_none()))
+            .ok_or_else(|| RequestError::InvalidToken {
+                reason: match &header.kid {
+                    Some(kid) => format!("Unknown key ID {kid:?}"),
+                    None => "Token has no key ID".to_owned(),
+                },
+            })?;
+
+        if key.algorithm != header.alg {
+            return Err(RequestError::InvalidToken {
+                reason: format!(
+                    "Key {:?} is for {:?}, not {:?}",
+                    key.kid.as_deref().unwrap_or_default(),
+                    key.algorithm,
+                    header.alg
+                ),
+            });
+        }
+
+        Ok(key)
+    }
+}
+
+/// Extract the password from HTTP Basic credentials.
+fn extract_auth_password(credentials: &str) -> Option<String> {
+    use base64::prelude::*;
+
+    let credentials = String::from_utf8(BASE64_STANDARD.decode(credentials).ok()?).ok()?;
+
+    let pw = credentials
+        .split_once(':')
+        .map(|(_user, password)| password.to_owned());
+
+    pw
+}
+
+/// Extract the token of a request. It can be the password of HTTP
+/// Basic authentication, which works with netrc, a Bearer token or, if
+/// enabled, a query parameter.
+fn extract_token(headers: &HeaderMap, uri: &Uri, query_parameter: Option<&str>) -> Option<String> {
+    if let Some(header_value) = headers
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+    {
+        // Authentication schemes are case-insensitive (RFC 7235).
+        let (scheme, credentials) = header_value.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+
+        return if scheme.eq_ignore_ascii_case("Bearer") {
+            Some(credentials.to_owned())
+        } else if scheme.eq_ignore_ascii_case("Basic") {
+            extract_auth_password(credentials)
+        } else {
+            None
+        };
+    }
+
+    let query_parameter = query_parameter?;
+    let Query(mut params) = Query::<HashMap<String, String>>::try_from_uri(uri).ok()?;
+    params.remove(query_parameter)
+}
+
+/// Check the token of a request and return what it grants access to.
+fn authorize(state: &AuthState, headers: &HeaderMap, uri: &Uri) -> Result<Access, RequestError> {
+    let jwt_str =
+        extract_token(headers, uri, state.query_parameter.as_deref()).ok_or_else(|| {
+            RequestError::InvalidToken {
+                reason: "Missing token".to_owned(),
+            }
+        })?;
+    let keys = state.keys.load();
+    let claim_options = &state.claim_options;
+    let invalid_token = |e: jsonwebtoken::errors::Error| RequestError::InvalidToken {
+        reason: e.to_string(),
+    };
+
+    let key = keys.select(&jsonwebtoken::decode_header(&jwt_str).map_err(invalid_token)?)?;
+
+    let validation = claim_options.validation(key.algorithm);
+    let claim = jsonwebtoken::decode::<Claims>(&jwt_str, &key.decoding_key, &validation)
+        .map_err(invalid_token)?;
+    debug!("Claim {:?}", claim);
+
+    claim_options.check_required(&claim.claims)?;
+
+    Ok(Access {
+        channels: claim.claims.channels,
+    })
+}
+
+/// If JWT public keys are available, make sure that each request is
+/// authorized.
+pub async fn auth_middleware(
+    State(state): State<Arc<AuthState>>,
+    mut request: Request,
+    next: Next,
+) -> response::Response {
+    match authorize(&state, request.headers(), request.uri()) {
+        Ok(access) => {
+            request.extensions_mut().insert(access);
+        }
+        Err(e) => {
+            info!("JWT validation error: {e}");
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_works() {
+        assert!(glob_match("nixos-25.05", "nixos-25.05"));
+        assert!(!glob_match("nixos-25.05", "nixos-25.050"));
+        assert!(glob_match("nixos-*", "nixos-25.05"));
+        assert!(glob_match("nixos-*", "nixos-"));
+        assert!(!glob_match("nixos-*", "nixpkgs"));
+        assert!(glob_match("*-25.05", "nixos-25.05"));
+        assert!(glob_match("customer-*-*", "customer-acme-prod"));
+        assert!(!glob_match("customer-*-*", "customer-acme"));
+        assert!(glob_match("nixos-2?.05", "nixos-24.05"));
+        assert!(!glob_match("nixos-2?.05", "nixos-2.05"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+    }
+
+    #[test]
+    fn keys_are_selected_by_kid() {
+        let key = |kid: Option<&str>, algorithm| JwtKey {
+            kid: kid.map(str::to_owned),
+            algorithm,
+            decoding_key: DecodingKey::from_secret(b"unused"),
+        };
+        let header = |kid: Option<&str>, alg| Header {
+            kid: kid.map(str::to_owned),
+            ..Header::new(alg)
+        };
+
+        let keys = JwtKeys::new(vec![
+            key(None, Algorithm::RS256),
+            key(Some("2025"), Algorithm::ES256),
+            key(Some("2026"), Algorithm::EdDSA),
+        ]);
+
+        let selected = |kid, alg| keys.select(&header(kid, alg)).map(|key| key.kid.clone());
+        assert_eq!(selected(None, Algorithm::RS256).unwrap(), None);
+        assert_eq!(
+            selected(Some("2026"), Algorithm::EdDSA).unwrap().as_deref(),
+            Some("2026")
+        );
+        assert!(selected(Some("2026"), Algorithm::ES256).is_err());
+        assert_eq!(selected(Some("2024"), Algorithm::RS256).unwrap(), None);
+        assert!(selected(Some("2024"), Algorithm::ES256).is_err());
+
+        let without_default = JwtKeys::new(vec![key(Some("2025"), Algorithm::ES256)]);
+        assert!(without_default
+            .select(&header(None, Algorithm::ES256))
+            .is_err());
+        assert!(without_default
+            .select(&header(Some("2024"), Algorithm::ES256))
+            .is_err());
+
+        // A --jwt-pem key verifies tokens from identity providers that
+        // always set a key ID.
+        let pem_only = JwtKeys::new(vec![key(None, Algorithm::RS256)]);
+        assert_eq!(
+            pem_only
+                .select(&header(Some("provider-key"), Algorithm::RS256))
+                .unwrap()
+                .kid,
+            None
+        );
+    }
+
+    #[test]
+    fn jwks_keys_are_loaded() {
+        let jwks: JwkSet = serde_json::from_str(
+            r#"{ "keys": [
+                { "kty": "OKP", "crv": "Ed25519", "kid": "ed",
+                  "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo" },
+                { "kty": "EC", "crv": "P-256", "kid": "ec", "alg": "ES256",
+                  "x": "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU",
+                  "y": "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0" },
+                { "kty": "EC", "crv": "P-384", "kid": "p384",
+                  "x": "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU",
+                  "y": "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0" },
+                { "kty": "oct", "kid": "secret", "alg": "HS256", "k": "c2VjcmV0" }
+            ] }"#,
+        )
+        .unwrap();
+
+        let keys = keys_from_jwks(&jwks)
+            .into_iter()
+            .map(|key| (key.kid, key.algorithm))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec![
+                (Some("ed".to_owned()), Algorithm::EdDSA),
+                (Some("ec".to_owned()), Algorithm::ES256),
+            ]
+        );
+    }
+
+    #[test]
+    fn required_claims_are_checked() {
+        let claim_options = ClaimOptions {
+            required_claims: vec!["sub".to_owned(), "channels".to_owned(), "team".to_owned()],
+            ..Default::default()
+        };
+        let claims = |json: &str| serde_json::from_str::<Claims>(json).unwrap();
+
+        assert!(claim_options
+            .check_required(&claims(r#"{ "channels": [], "team": "infra" }"#))
+            .is_ok());
+        assert!(claim_options
+            .check_required(&claims(r#"{ "team": "infra" }"#))
+            .is_err());
+        assert!(claim_options
+            .check_required(&claims(r#"{ "channels": [] }"#))
+            .is_err());
+
+        let validation = claim_options.validation(Algorithm::RS256);
+        assert!(validation.required_spec_claims.contains("exp"));
+        assert!(validation.required_spec_claims.contains("sub"));
+        assert!(!validation.required_spec_claims.contains("team"));
+        assert!(!validation.validate_aud);
+    }
+
+    #[test]
+    fn tokens_are_extracted() {
+        use base64::prelude::*;
+
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+        let uri = |uri: &str| uri.parse::<Uri>().unwrap();
+        let basic = format!("Basic {}", BASE64_STANDARD.encode("user:a.b.c"));
+
+        assert_eq!(
+            extract_token(&headers(&basic), &uri("/channel/foo"), None).as_deref(),
+            Some("a.b.c")
+        );
+        assert_eq!(
+            extract_token(&headers("Bearer a.b.c"), &uri("/channel/foo"), None).as_deref(),
+            Some("a.b.c")
+        );
+        assert_eq!(
+            extract_token(&headers("bearer a.b.c"), &uri("/channel/foo"), None).as_deref(),
+            Some("a.b.c")
+        );
+        assert_eq!(
+            extract_token(
+                &headers(&basic.replace("Basic", "BASIC")),
+                &uri("/channel/foo"),
+                None
+            )
+            .as_deref(),
+            Some("a.b.c")
+        );
+        assert_eq!(
+            extract_token(&headers("Digest foo"), &uri("/channel/foo"), None),
+            None
+        );
+
+        let query_uri = uri("/channel/foo?at=2025-01-01T00:00:00Z&token=a.b.c");
+        assert_eq!(extract_token(&HeaderMap::new(), &query_uri, None), None);
+        assert_eq!(
+            extract_token(&HeaderMap::new(), &query_uri, Some("token")).as_deref(),
+            Some("a.b.c")
+        );
+    }
+
+    #[test]
+    fn key_specs_are_parsed() {
+        let spec: JwtKeySpec = "2025:ES256:/etc/keys/2025.pem".parse().unwrap();
+        assert_eq!(spec.kid, "2025");
+        assert_eq!(spec.algorithm, Algorithm::ES256);
+        assert_eq!(spec.pem_file, PathBuf::from("/etc/keys/2025.pem"));
+
+        assert!("2025:HS256:key.pem".parse::<JwtKeySpec>().is_err());
+        assert!(":RS256:key.pem".parse::<JwtKeySpec>().is_err());
+        assert!("2025:RS256".parse::<JwtKeySpec>().is_err());
+    }
+
+    #[test]
+    fn access_is_restricted_by_patterns() {
+        assert!(Access::default().allows("anything"));
+
+        let access = Access {
+            channels: Some(vec!["customer-acme-*".to_owned(), "public".to_owned()]),
+        };
+        assert!(access.allows("customer-acme-25.05"));
+        assert!(access.allows("public"));
+        assert!(!access.allows("customer-other-25.05"));
+        assert!(access.check("customer-other-25.05").is_err());
+
+        let nothing = Access {
+            channels: Some(vec![]),
+        };
+        assert!(!nothing.allows("public"));
+    }
+}
@@ -0,0 +1,134 @@
+//! A human-readable overview of the channels we serve.
+
+use std::{fmt::Write, sync::Arc};
+
+use axum::{extract::State, response::Html};
+use time::format_description::well_known::Rfc3339;
+
+use s3_nix_channel::persistent::{ChannelConfig, ChannelEntry};
+
+use crate::{auth::Access, Config};
+
+/// How many previous versions we list per channel.
+const RECENT_HISTORY: usize = 5;
+
+/// Escape text for use in HTML content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Describe a channel element in one line.
+fn describe_entry(entry: &ChannelEntry) -> String {
+    let mut description = format!("<code>{}</code>", escape(&entry.name));
+
+    if let Some(published) = entry.published.and_then(|p| p.format(&Rfc3339).ok()) {
+        let _ = write!(description, ", published {}", escape(&published));
+    }
+    if let Some(git_revision) = &entry.git_revision {
+        let _ = write!(
+            description,
+            ", revision <code>{}</code>",
+            escape(git_revision)
+        );
+    }
+
+    description
+}
+
+fn render_channel(html: &mut String, base_url: &str, name: &str, channel_config: &ChannelConfig) {
+    let url = format!("{base_url}/channel/{name}{}", channel_config.file_extension);
+
+    let _ = write!(
+        html,
+        "<section>\n<h2>{}</h2>\n<pre>inputs.{}.url = \"{}\";</pre>\n",
+        escape(name),
+        // Flake input names can't contain periods.
+        escape(&name.replace('.', "_")),
+        escape(&url),
+    );
+
+    match &channel_config.latest {
+        Some(latest) => {
+            let _ = writeln!(html, "<p>Latest: {}</p>", describe_entry(latest));
+        }
+        None => html.push_str("<p>Nothing published yet.</p>\n"),
+    }
+
+    let recent = channel_config
+        .history()
+        .enumerate()
+        .skip(1)
+        .take(RECENT_HISTORY)
+        .collect::<Vec<_>>();
+
+    if !recent.is_empty() {
+        html.push_str("<p>Previous versions:</p>\n<ul>\n");
+        for (index, entry) in recent {
+            let _ = writeln!(
+                html,
+                "<li><a href=\"{}\">~{index}</a>: {}</li>",
+                escape(&format!(
+                    "{base_url}/channel/{name}~{index}{}",
+                    channel_config.file_extension
+                )),
+                describe_entry(entry)
+            );
+        }
+        html.push_str("</ul>\n");
+    }
+
+    html.push_str("</section>\n");
+}
+
+/// List all accessible channels with their flake URL and recent
+/// history.
+pub async fn handle_index(State(config): State<Arc<Config>>, access: Access) -> Html<String> {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Nix Channels</title>\n</head>\n<body>\n<h1>Nix Channels</h1>\n",
+    );
+
+    let channels = config.channels.load();
+    let mut channels = channels
+        .channels()
+        .filter(|(name, _)| access.allows(name))
+        .peekable();
+
+    if channels.peek().is_none() {
+        html.push_str("<p>There are no channels.</p>\n");
+    }
+
+    for (name, channel_config) in channels {
+        render_channel(&mut html, &config.base_url, name, channel_config);
+    }
+
+    html.push_str("</body>\n</html>\n");
+
+    Html(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_works() {
+        assert_eq!(escape("nixos-25.05"), "nixos-25.05");
+        assert_eq!(
+            escape("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+}
@@ -1,30 +1,61 @@
+mod api;
+mod auth;
+mod html;
+
 use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use arc_swap::ArcSwap;
 use axum::{
-    extract::{Path, Request, State},
-    http::{header::LINK, HeaderMap, HeaderValue, Method, StatusCode},
+    body::Body,
+    extract::{Path, Query, Request, State},
+    http::{
+        header::{ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, LINK, RANGE},
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
     middleware::{self, Next},
     response::{self, IntoResponse, Redirect},
     routing::get,
     Router,
 };
 use clap::Parser;
-use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use jsonwebtoken::Algorithm;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 use tokio::time::interval;
-use tower_http::trace::TraceLayer;
+use tower::ServiceExt;
+use tower_http::{services::ServeFile, trace::TraceLayer};
 use tracing::{debug, error, info, warn};
 
-use s3_nix_channel::{error::RequestError, persistent::ChannelsConfig};
+use auth::{Access, AuthState, ClaimOptions, JwtKey, JwtKeySpec, JwtKeys, KeySources};
+use s3_nix_channel::{
+    error::RequestError,
+    persistent::{
+        ChannelConfig, ChannelEntry, ChannelsConfig, Client, DEFAULT_PRESIGN_EXPIRY,
+        MAX_PRESIGN_EXPIRY,
+    },
+    storage::{Delivery, S3Options},
+};
 
 /// A program to serve a S3 bucket via the Nix Lockable Tarball Protocol.
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// The S3 bucket to serve the content from.
-    #[arg(long)]
-    bucket: String,
+    #[arg(long, required_unless_present = "storage_dir")]
+    bucket: Option<String>,
+
+    /// Serve the content from a local directory instead of an S3
+    /// bucket.
+    #[arg(long, conflicts_with = "bucket")]
+    storage_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    s3: S3Options,
+
+    /// Serve only objects below this key prefix. This allows to host
+    /// several channel trees in one bucket.
+    #[arg(long, default_value = "")]
+    prefix: String,
 
     /// The base URL of the service.
     ///
@@ -47,52 +78,146 @@ struct Args {
     #[arg(long)]
     listen: Option<String>,
 
+    /// How long presigned URLs stay valid in seconds. Channels can
+    /// override this with presign_expiry_seconds. S3 allows at most
+    /// a week.
+    #[arg(long, default_value_t = DEFAULT_PRESIGN_EXPIRY.as_secs())]
+    presign_expiry: u64,
+
+    /// Stream files through this server instead of redirecting clients
+    /// to presigned URLs. This is useful, if clients cannot reach the
+    /// storage backend. Channels can override this with the proxy
+    /// setting.
+    #[arg(long)]
+    proxy: bool,
+
     /// Enable authentication using JWT by specifying the public key
     /// for token verification.
     #[arg(long)]
     jwt_pem: Option<PathBuf>,
+
+    /// Enable authentication using JWT with a public key for tokens
+    /// that carry the given key ID in their header. Specify as
+    /// <KID>:<ALGORITHM>:<PEM-FILE>, where the algorithm is RS256, ES256
+    /// or EdDSA. Can be given several times, e.g. to rotate keys.
+    #[arg(long)]
+    jwt_key: Vec<JwtKeySpec>,
+
+    /// Enable authentication using JWT with the keys of a JSON Web Key
+    /// Set in this file. Tokens select keys by their key ID. The file is
+    /// reloaded every config_update_seconds, so keys can be added or
+    /// revoked without a restart.
+    #[arg(long)]
+    jwt_jwks: Option<PathBuf>,
+
+    #[command(flatten)]
+    claim_options: ClaimOptions,
+
+    /// Also accept tokens in this query parameter, e.g. for downloads
+    /// in a browser. Tokens in URLs may end up in logs and browser
+    /// histories, so this is off by default.
+    #[arg(long)]
+    jwt_query_parameter: Option<String>,
 }
 
 struct Config {
-    s3_client: s3_nix_channel::persistent::Client,
+    s3_client: Client,
     base_url: String,
+    proxy: bool,
     update_interval: Duration,
     channels: ArcSwap<ChannelsConfig>,
 }
 
-/// Redirect to the latest tarball of the requested channel.
-async fn handle_channel(
-    method: Method,
-    Path(path): Path<String>,
-    State(config): State<Arc<Config>>,
-) -> Result<impl IntoResponse, RequestError> {
-    let channel_config = {
-        let channels_config = config.channels.load();
+/// Stream an object from the storage backend to the client.
+async fn proxy(
+    config: &Config,
+    object_key: &str,
+    request_headers: &HeaderMap,
+) -> Result<response::Response, RequestError> {
+    let range = request_headers
+        .get(RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map(str::to_owned);
+    let object = config
+        .s3_client
+        .read_stream(object_key, range.as_deref())
+        .await?;
 
-        // TODO Needlessly inefficient. But we cannot use split_filename here,
-        // because channel names often include periods, such as foobar-24.05.
-        // :-(
-        let (_, channel_config) = channels_config
-            .channels()
-            .find(|(k, v)| {
-                debug!("{path} vs {k}{}", v.file_extension);
-                path == format!("{k}{}", v.file_extension)
-            })
-            .ok_or_else(|| RequestError::NoSuchChannel {
-                file_name: path.clone(),
-            })?;
-        channel_config.clone()
+    let mut headers = HeaderMap::new();
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(
+        CONTENT_TYPE,
+        object
+            .content_type
+            .as_deref()
+            .and_then(|content_type| HeaderValue::from_str(content_type).ok())
+            .unwrap_or(HeaderValue::from_static("application/octet-stream")),
+    );
+
+    if let Some(content_length) = object.content_length {
+        headers.insert(CONTENT_LENGTH, content_length.into());
+    }
+
+    for (name, value) in [
+        (ETAG, &object.e_tag),
+        (CONTENT_RANGE, &object.content_range),
+    ] {
+        if let Some(value) = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            headers.insert(name, value);
+        }
+    }
+
+    let status = if object.content_range.is_some() {
+        StatusCode::PARTIAL_CONTENT
+    } else {
+        StatusCode::OK
     };
 
-    let latest_object = &channel_config
-        .latest
-        // We could have a specific error here, but this only happens
-        // when a channel is initially set up. It becomes visible once
-        // it has content.
-        .ok_or_else(|| RequestError::NoSuchChannel {
-            file_name: path.clone(),
-        })?;
+    Ok((status, headers, object.body).into_response())
+}
 
+/// Send the content of an object to the client. Usually, this is a
+/// redirect to the storage backend.
+async fn deliver(
+    config: &Config,
+    method: Method,
+    object_key: &str,
+    channel_config: Option<&ChannelConfig>,
+    request: Request<Body>,
+) -> Result<response::Response, RequestError> {
+    let expires_in = channel_config.and_then(ChannelConfig::presign_expiry);
+    let proxy_enabled = channel_config
+        .and_then(|channel_config| channel_config.proxy)
+        .unwrap_or(config.proxy);
+
+    match config
+        .s3_client
+        .deliver(method, object_key, expires_in, proxy_enabled)
+        .await?
+    {
+        Delivery::Proxy => proxy(config, object_key, request.headers()).await,
+        Delivery::Redirect(url) => Ok(Redirect::temporary(&url).into_response()),
+        // ServeFile takes care of HEAD and Range requests for us.
+        Delivery::File(path) => Ok(ServeFile::new(path).oneshot(request).await.into_response()),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ChannelQuery {
+    /// Resolve the channel as it was at this point in time (RFC 3339).
+    at: Option<String>,
+}
+
+/// Send a file of a channel element to the client, together with the
+/// link to its immutable URL.
+async fn deliver_entry(
+    config: &Config,
+    method: Method,
+    channel_config: &ChannelConfig,
+    entry: &ChannelEntry,
+    file_extension: &str,
+    request: Request<Body>,
+) -> Result<response::Response, RequestError> {
     let mut headers = HeaderMap::new();
 
     // The Lockable HTTP Tarball Protocol. See:
@@ -100,84 +225,173 @@ async fn handle_channel(
     headers.insert(
         LINK,
         HeaderValue::from_str(&format!(
-            "<{}/permanent/{latest_object}{}>; rel=\"immutable\"",
-            config.base_url, channel_config.file_extension
+            "<{}/permanent/{}{}{}>; rel=\"immutable\"",
+            config.base_url,
+            entry.name,
+            file_extension,
+            entry.flake_query()
         ))
         .map_err(|_e| RequestError::Unknown)?,
     );
 
     Ok((
         headers,
-        Redirect::temporary(
-            &config
-                .s3_client
-                .sign_request(
-                    method,
-                    &format!("{latest_object}{}", channel_config.file_extension),
-                )
-                .await?,
-        ),
-    ))
-}
-
-#[derive(Debug, serde::Deserialize)]
-struct Claims {
-    // We need nothing.
+        deliver(
+            config,
+            method,
+            &format!("{}{file_extension}", entry.name),
+            Some(channel_config),
+            request,
+        )
+        .await?,
+    )
+        .into_response())
 }
 
-/// Extract the HTTP Basic Authorization password.
-fn extract_auth_password(headers: &HeaderMap) -> Option<String> {
-    use base64::prelude::*;
+/// Serve the directory layout that nix-channel expects. Each channel is
+/// a directory with the latest tarball as nixexprs.tar.xz and
+/// optionally the binary cache it should use.
+async fn handle_legacy_channel(
+    config: &Config,
+    method: Method,
+    access: &Access,
+    channel_name: &str,
+    file_name: &str,
+    request: Request<Body>,
+) -> Result<response::Response, RequestError> {
+    let no_such_channel = || RequestError::NoSuchChannel {
+        file_name: if file_name.is_empty() {
+            channel_name.to_owned()
+        } else {
+            format!("{channel_name}/{file_name}")
+        },
+    };
+    let channels_config = config.channels.load();
+    let channel_name = channels_config.resolve_alias(channel_name);
+    let channel_config = channels_config
+        .channel(channel_name)
+        .ok_or_else(no_such_channel)?;
 
-    // Get the Authorization header value
-    let header = headers.get("Authorization")?;
-    let header_value = header.to_str().ok()?;
+    access.check(channel_name)?;
 
-    let credentials = header_value.strip_prefix("Basic ")?.to_owned();
-    let credentials = String::from_utf8(BASE64_STANDARD.decode(&credentials).ok()?).ok()?;
+    // nix-channel only knows about xz-compressed tarballs.
+    if channel_config.file_extension != ".tar.xz" {
+        return Err(no_such_channel());
+    }
 
-    let pw = credentials
-        .split_once(':')
-        .map(|(_user, password)| password.to_owned());
+    match file_name {
+        // nix-channel requests the channel URL itself first to follow
+        // redirects. Redirecting here would send it to S3, so we list
+        // the directory instead.
+        "" => {
+            let mut index = String::from("nixexprs.tar.xz\n");
+            if channel_config.binary_cache_url.is_some() {
+                index.push_str("binary-cache-url\n");
+            }
 
-    pw
+            Ok(index.into_response())
+        }
+        "nixexprs.tar.xz" => {
+            let latest = channel_config.latest.as_ref().ok_or_else(no_such_channel)?;
+
+            deliver_entry(
+                config,
+                method,
+                &channel_config,
+                latest,
+                &channel_config.file_extension,
+                request,
+            )
+            .await
+        }
+        "binary-cache-url" => channel_config
+            .binary_cache_url
+            .clone()
+            .map(IntoResponse::into_response)
+            .ok_or_else(no_such_channel),
+        _ => Err(no_such_channel()),
+    }
 }
 
-/// If a JWT public key is available, make sure that each request is authorized.
-async fn auth_middleware(
-    State(decoding_key): State<DecodingKey>,
-    request: Request,
-    next: Next,
-) -> response::Response {
-    let mut validation = Validation::new(Algorithm::RS256);
-    validation.validate_nbf = true;
-
-    // TODO What we validate in the claims should be configurable. For
-    // now we just check whether the token is signed and valid.
-    validation.validate_aud = false;
-    validation.set_required_spec_claims(&["exp"]);
-
-    match extract_auth_password(request.headers())
-        .ok_or_else(|| RequestError::InvalidToken {
-            reason: "Missing Authorization header".to_owned(),
-        })
-        .and_then(|jwt_str| {
-            jsonwebtoken::decode::<Claims>(&jwt_str, &decoding_key, &validation).map_err(|e| {
-                RequestError::InvalidToken {
-                    reason: e.to_string(),
-                }
-            })
-        }) {
-        Ok(claim) => {
-            debug!("Claim {:?}", claim)
-        }
-        Err(e) => {
-            info!("JWT validation error: {e}");
-            return StatusCode::UNAUTHORIZED.into_response();
+/// Redirect to the latest tarball of the requested channel.
+async fn handle_channel(
+    method: Method,
+    Path(path): Path<String>,
+    Query(query): Query<ChannelQuery>,
+    State(config): State<Arc<Config>>,
+    access: Access,
+    request: Request<Body>,
+) -> Result<response::Response, RequestError> {
+    // Channel names never contain slashes, so this can only be the
+    // nix-channel layout. The same goes for bare channel names.
+    let legacy = match path.split_once('/') {
+        Some(legacy) => Some(legacy),
+        None if config
+            .channels
+            .load()
+            .channel_for_file_name(&path)
+            .is_none() =>
+        {
+            Some((path.as_str(), ""))
         }
+        None => None,
+    };
+    if let Some((channel_name, file_name)) = legacy {
+        return handle_legacy_channel(&config, method, &access, channel_name, file_name, request)
+            .await;
     }
 
-    next.run(request).await
+    let at = query
+        .at
+        .map(|at| {
+            OffsetDateTime::parse(&at, &Rfc3339).map_err(|e| RequestError::InvalidQuery {
+                reason: format!("Invalid timestamp {at:?}: {e}"),
+            })
+        })
+        .transpose()?;
+
+    let (channel_config, entry, file_extension) = {
+        let channels_config = config.channels.load();
+
+        let (channel_name, channel_config, index, file_extension) = channels_config
+            .channel_for_file_name(&path)
+            .ok_or_else(|| RequestError::NoSuchChannel {
+                file_name: path.clone(),
+            })?;
+
+        access.check(channel_name)?;
+
+        // The history index counts back from the element that was the
+        // latest one at the requested time.
+        let start = match at {
+            Some(at) => channel_config.index_at(at),
+            None => Some(0),
+        };
+
+        // A missing latest element only happens when a channel is
+        // initially set up. It becomes visible once it has content.
+        // Older elements may lack files of extensions that were added
+        // later.
+        let entry = start
+            .and_then(|start| channel_config.nth_latest(start + index))
+            .filter(|entry| channel_config.provides(entry, file_extension))
+            .ok_or_else(|| RequestError::NoSuchChannel {
+                file_name: path.clone(),
+            })?
+            .clone();
+
+        (channel_config.clone(), entry, file_extension.to_owned())
+    };
+
+    deliver_entry(
+        &config,
+        method,
+        &channel_config,
+        &entry,
+        &file_extension,
+        request,
+    )
+    .await
 }
 
 async fn log_request_middleware(req: Request, next: Next) -> response::Response {
@@ -202,22 +416,38 @@ async fn log_request_middleware(req: Request, next: Next) -> response::Response
 }
 
 /// Forward a request to the backing store.
+///
+/// We only serve objects that are the latest or a previous element of
+/// one of our channels. Everything else in the bucket stays private.
+/// The client needs access to that channel.
 async fn handle_persistent(
     method: Method,
     Path(path): Path<String>,
     State(config): State<Arc<Config>>,
+    access: Access,
+    request: Request<Body>,
 ) -> Result<impl IntoResponse, RequestError> {
-    // TODO This is very unfortunate. We basically allow the client to grab
-    // everything from the bucket here. This would include our config files as
-    // well. There are no secrets in them, but it's still not great. We make a
-    // half-hearted attempt at preventing it here...
-    if path.ends_with(".json") {
-        return Err(RequestError::NoSuchChannel { file_name: path });
-    }
+    let channel_config = {
+        let channels_config = config.channels.load();
+        let (channel_name, _) = channels_config.channel_for_object(&path).ok_or_else(|| {
+            RequestError::NoSuchObject {
+                object_key: path.clone(),
+            }
+        })?;
+
+        // Hand-edited configurations may share objects between
+        // channels. Access to any of them is enough.
+        let accessible = channels_config
+            .channels()
+            .find(|(name, channel)| channel.contains_object(&path) && access.allows(name))
+            .map(|(_, channel_config)| channel_config.clone());
+
+        accessible.ok_or_else(|| RequestError::AccessDenied {
+            channel_name: channel_name.to_owned(),
+        })?
+    };
 
-    Ok(Redirect::temporary(
-        &config.s3_client.sign_request(method, &path).await?,
-    ))
+    deliver(&config, method, &path, Some(&channel_config), request).await
 }
 
 /// Poll the bucket for changes of the configuration.
@@ -243,6 +473,70 @@ async fn poll_config_file(state: &Config) {
     }
 }
 
+/// Load all public keys for token verification. Without keys, there is
+/// no authentication.
+fn load_jwt_keys(
+    jwt_pem: Option<&std::path::Path>,
+    jwt_keys: &[JwtKeySpec],
+) -> Result<Vec<JwtKey>> {
+    let key_files = jwt_pem
+        .map(|pem_file| (None, Algorithm::RS256, pem_file))
+        .into_iter()
+        .chain(jwt_keys.iter().map(|spec| {
+            (
+                Some(spec.kid.clone()),
+                spec.algorithm,
+                spec.pem_file.as_path(),
+            )
+        }));
+
+    let mut keys = Vec::new();
+    for (kid, algorithm, pem_file) in key_files {
+        // Be sure to handle the I/O error, so we don't accidentally
+        // misinterpret "couldn't read file" as "there is no public
+        // key", which would make the service accessible without
+        // authentication.
+        let pem_data = std::fs::read(pem_file).with_context(|| {
+            format!("Failed to read public key PEM from {}", pem_file.display())
+        })?;
+
+        keys.push(
+            JwtKey::from_pem(kid, algorithm, &pem_data)
+                .with_context(|| format!("Failed to decode public key {}", pem_file.display()))?,
+        );
+    }
+
+    Ok(keys)
+}
+
+/// Poll the JWKS file for added or revoked keys.
+async fn poll_jwks_file(sources: &KeySources, keys: &ArcSwap<JwtKeys>, update_interval: Duration) {
+    let mut interval = interval(update_interval);
+
+    // The first tick completes immediately, so we skip it.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        match sources.load() {
+            Ok(new_keys) => keys.store(Arc::new(new_keys)),
+            Err(e) => error!("Failed to reload JWT keys (will try again later): {e:#}"),
+        }
+    }
+}
+
+fn router(config: Arc<Config>) -> Router {
+    Router::new()
+        .route("/", get(html::handle_index))
+        .route("/channel/", get(html::handle_index))
+        .route("/channel/{*path}", get(handle_channel))
+        .route("/permanent/{*path}", get(handle_persistent))
+        .route("/api/channels", get(api::handle_channels))
+        .route("/api/channels/{name}", get(api::handle_channel_info))
+        .with_state(config)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -253,27 +547,33 @@ async fn main() -> Result<()> {
         )
         .init();
 
-    let s3_client = s3_nix_channel::persistent::Client::new_from_env(&args.bucket).await?;
+    let presign_expiry = Duration::from_secs(args.presign_expiry);
+    if presign_expiry.is_zero() || presign_expiry > MAX_PRESIGN_EXPIRY {
+        bail!(
+            "--presign-expiry must be between 1 and {} seconds",
+            MAX_PRESIGN_EXPIRY.as_secs()
+        );
+    }
+
+    let s3_client = match (&args.bucket, &args.storage_dir) {
+        (_, Some(storage_dir)) => Client::new_local(storage_dir)?,
+        (Some(bucket), None) => Client::new_s3(bucket, args.s3.clone()).await?,
+        // Clap makes sure that one of them is present.
+        (None, None) => unreachable!(),
+    }
+    .with_prefix(&args.prefix)
+    .with_presign_expiry(presign_expiry);
 
     let channels = s3_client.load_channels_config().await?;
-    let jwt_public_key = args
-        .jwt_pem
-        .map(|pem_file| {
-            std::fs::read(&pem_file).with_context(|| {
-                format!("Failed to read public key PEM from {}", pem_file.display())
-            })
-        })
-        // Be sure to handle the I/O error, so we don't accidentally
-        // misinterpret "couldn't read file" as "there is no public
-        // key", which would make the service accessible without
-        // authentication.
-        .transpose()?
-        .map(|pem_data| DecodingKey::from_rsa_pem(&pem_data).context("Failed to decode public key"))
-        .transpose()?;
+    let key_sources = KeySources {
+        static_keys: load_jwt_keys(args.jwt_pem.as_deref(), &args.jwt_key)?,
+        jwks_file: args.jwt_jwks,
+    };
 
     let config = Arc::new(Config {
         s3_client,
         base_url: args.base_url,
+        proxy: args.proxy,
         update_interval: Duration::from_secs(args.config_update_seconds),
         channels: ArcSwap::new(Arc::new(channels)),
     });
@@ -285,13 +585,27 @@ async fn main() -> Result<()> {
     });
 
     // TODO Add proper logging of requests.
-    let mut app = Router::new()
-        .route("/channel/{*path}", get(handle_channel))
-        .route("/permanent/{*path}", get(handle_persistent))
-        .with_state(config);
+    let mut app = router(config);
+
+    if !key_sources.is_empty() {
+        // Failing to load the keys initially is fatal. Otherwise, we
+        // might start without any keys.
+        let jwt_keys = Arc::new(ArcSwap::from_pointee(key_sources.load()?));
+
+        if key_sources.jwks_file.is_some() {
+            let update_keys = jwt_keys.clone();
+            let update_interval = Duration::from_secs(args.config_update_seconds);
+            tokio::spawn(async move {
+                poll_jwks_file(&key_sources, &update_keys, update_interval).await;
+            });
+        }
 
-    if let Some(jwt_public_key) = jwt_public_key {
-        let auth_layer = middleware::from_fn_with_state(jwt_public_key, auth_middleware);
+        let auth_state = Arc::new(AuthState {
+            keys: jwt_keys,
+            claim_options: args.claim_options,
+            query_parameter: args.jwt_query_parameter,
+        });
+        let auth_layer = middleware::from_fn_with_state(auth_state, auth::auth_middleware);
 
         app = app.layer(auth_layer);
     }
@@ -340,3 +654,152 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use axum::body::{to_bytes, Bytes};
+    use s3_nix_channel::storage::{MemoryStorage, Storage};
+
+    /// Serve a memory bucket with the given objects.
+    async fn test_router(objects: &[(&str, &str)]) -> Router {
+        let storage = MemoryStorage::new();
+        for (object_key, data) in objects {
+            storage
+                .write(object_key, Bytes::from(data.to_string()))
+                .await
+                .unwrap();
+        }
+
+        let s3_client = Client::new(storage);
+        let channels = s3_client.load_channels_config().await.unwrap();
+
+        router(Arc::new(Config {
+            s3_client,
+            base_url: "https://example.com".to_owned(),
+            proxy: false,
+            update_interval: Duration::from_secs(3600),
+            channels: ArcSwap::from_pointee(channels),
+        }))
+    }
+
+    async fn send(router: &Router, method: Method, uri: &str) -> response::Response {
+        router
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap()
+    }
+
+    async fn body_text(response: response::Response) -> String {
+        String::from_utf8(to_bytes(response.into_body(), 4096).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn proxying_works() {
+        let router = test_router(&[
+            ("channels.json", r#"{ "channels": ["nixos", "images"] }"#),
+            ("nixos.json", r#"{ "latest": "nixos-1", "proxy": true }"#),
+            ("nixos-1.tar.xz", "tarball"),
+            (
+                "images.json",
+                r#"{ "latest": "images-1", "file_extension": ".iso" }"#,
+            ),
+            ("images-1.iso", "image"),
+        ])
+        .await;
+
+        let response = send(&router, Method::GET, "/channel/nixos.tar.xz").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_LENGTH], "7");
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/octet-stream");
+        assert_eq!(
+            response.headers()[LINK],
+            "<https://example.com/permanent/nixos-1.tar.xz>; rel=\"immutable\""
+        );
+        assert_eq!(body_text(response).await, "tarball");
+
+        let response = send(&router, Method::GET, "/permanent/nixos-1.tar.xz").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_text(response).await, "tarball");
+
+        // Channels without proxying still redirect.
+        let response = send(&router, Method::GET, "/channel/images.iso").await;
+        assert!(response.status().is_redirection());
+        assert_eq!(response.headers()["location"], "memory:///images-1.iso");
+    }
+
+    #[tokio::test]
+    async fn legacy_channels_work() {
+        let router = test_router(&[
+            (
+                "channels.json",
+                r#"{ "channels": ["nixos", "images"], "aliases": { "stable": "nixos" } }"#,
+            ),
+            (
+                "nixos.json",
+                r#"{ "latest": "nixos-1", "binary_cache_url": "https://cache.example.com" }"#,
+            ),
+            ("nixos-1.tar.xz", "tarball"),
+            (
+                "images.json",
+                r#"{ "latest": "images-1", "file_extension": ".iso" }"#,
+            ),
+            ("images-1.iso", "image"),
+        ])
+        .await;
+
+        // nix-channel requests the channel URL itself before anything
+        // else, with and without trailing slash.
+        for uri in ["/channel/nixos", "/channel/nixos/", "/channel/stable"] {
+            let response = send(&router, Method::GET, uri).await;
+            assert_eq!(response.status(), StatusCode::OK, "{uri}");
+            assert_eq!(
+                body_text(response).await,
+                "nixexprs.tar.xz\nbinary-cache-url\n"
+            );
+        }
+        assert_eq!(
+            send(&router, Method::HEAD, "/channel/nixos").await.status(),
+            StatusCode::OK
+        );
+
+        let response = send(&router, Method::GET, "/channel/nixos/nixexprs.tar.xz").await;
+        assert!(response.status().is_redirection());
+        assert_eq!(
+            response.headers()[LINK],
+            "<https://example.com/permanent/nixos-1.tar.xz>; rel=\"immutable\""
+        );
+
+        let response = send(&router, Method::GET, "/channel/nixos/binary-cache-url").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_text(response).await, "https://cache.example.com");
+
+        // The regular layout still works.
+        assert!(send(&router, Method::GET, "/channel/nixos.tar.xz")
+            .await
+            .status()
+            .is_redirection());
+
+        for uri in [
+            "/channel/unknown",
+            "/channel/unknown/",
+            "/channel/nixos/unknown",
+            "/channel/images",
+            "/channel/images/nixexprs.tar.xz",
+        ] {
+            assert_eq!(
+                send(&router, Method::GET, uri).await.status(),
+                StatusCode::NOT_FOUND,
+                "{uri}"
+            );
+        }
+    }
+}
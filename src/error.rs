@@ -1,8 +1,65 @@
+use std::path::PathBuf;
+
 use axum::{
-    http::{self, StatusCode},
+    http::{self, header::CONTENT_RANGE, HeaderValue, StatusCode},
     response::IntoResponse,
 };
 
+/// Errors from the persistent storage and the channel logic on top of
+/// it.
+#[derive(thiserror::Error, Debug)]
+pub enum PersistentError {
+    #[error("There is no such object: {object_key:?}")]
+    NotFound { object_key: String },
+    #[error("Refusing to overwrite existing object: {object_key:?}")]
+    AlreadyExists { object_key: String },
+    #[error("Object {object_key:?} was modified concurrently")]
+    Conflict { object_key: String },
+    #[error("Access denied to object {object_key:?}")]
+    PermissionDenied { object_key: String },
+    #[error("Failed to deserialize {object_key:?}: {source}")]
+    Deserialization {
+        object_key: String,
+        source: serde_json::Error,
+    },
+    #[error("Failed to serialize {object_key:?}: {source}")]
+    Serialization {
+        object_key: String,
+        source: serde_json::Error,
+    },
+    #[error("Failed to access storage backend: {reason}")]
+    Transport { reason: String },
+    #[error("I/O error for {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{reason}")]
+    InvalidInput { reason: String },
+    #[error("Requested range of object {object_key:?} is not satisfiable")]
+    RangeNotSatisfiable {
+        object_key: String,
+        /// The Content-Range header of the backend, which contains the
+        /// size of the object.
+        content_range: Option<String>,
+    },
+}
+
+impl PersistentError {
+    /// Classify an I/O error on a file in the filesystem.
+    pub(crate) fn from_io(object_key: &str, path: PathBuf, source: std::io::Error) -> Self {
+        match source.kind() {
+            std::io::ErrorKind::NotFound => PersistentError::NotFound {
+                object_key: object_key.to_owned(),
+            },
+            std::io::ErrorKind::PermissionDenied => PersistentError::PermissionDenied {
+                object_key: object_key.to_owned(),
+            },
+            _ => PersistentError::Io { path, source },
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum RequestError {
     #[error("Failed to presign request for object {object_key:?}")]
@@ -11,7 +68,20 @@ pub enum RequestError {
     PresignConfigFailure,
     #[error("There is no such channel: {file_name:?}")]
     NoSuchChannel { file_name: String },
+    #[error("There is no such object: {object_key:?}")]
+    NoSuchObject { object_key: String },
+    #[error("Requested range of object {object_key:?} is not satisfiable")]
+    RangeNotSatisfiable {
+        object_key: String,
+        content_range: Option<String>,
+    },
+    #[error("Storage failure: {source}")]
+    Storage { source: PersistentError },
 
+    #[error("Invalid query: {reason}")]
+    InvalidQuery { reason: String },
+    #[error("No access to channel {channel_name:?}")]
+    AccessDenied { channel_name: String },
     #[error("Invalid token: {reason}")]
     InvalidToken { reason: String },
     #[error("Unsupported HTTP method: {method}")]
@@ -20,19 +90,73 @@ pub enum RequestError {
     Unknown,
 }
 
+impl From<PersistentError> for RequestError {
+    fn from(source: PersistentError) -> Self {
+        match source {
+            PersistentError::NotFound { object_key } => RequestError::NoSuchObject { object_key },
+            PersistentError::RangeNotSatisfiable {
+                object_key,
+                content_range,
+            } => RequestError::RangeNotSatisfiable {
+                object_key,
+                content_range,
+            },
+            source => RequestError::Storage { source },
+        }
+    }
+}
+
 impl IntoResponse for RequestError {
     fn into_response(self) -> axum::response::Response {
+        // Clients need the object size to fix their range.
+        let content_range = match &self {
+            RequestError::RangeNotSatisfiable {
+                object_key: _,
+                content_range: Some(content_range),
+            } => HeaderValue::from_str(content_range).ok(),
+            _ => None,
+        };
+
         (
             match self {
-                RequestError::NoSuchChannel { file_name: _ } => StatusCode::NOT_FOUND,
-                RequestError::InvalidToken { reason: _ } => StatusCode::FORBIDDEN,
+                RequestError::NoSuchChannel { file_name: _ }
+                | RequestError::NoSuchObject { object_key: _ } => StatusCode::NOT_FOUND,
+                RequestError::RangeNotSatisfiable {
+                    object_key: _,
+                    content_range: _,
+                } => StatusCode::RANGE_NOT_SATISFIABLE,
+                RequestError::InvalidQuery { reason: _ } => StatusCode::BAD_REQUEST,
+                RequestError::InvalidToken { reason: _ }
+                | RequestError::AccessDenied { channel_name: _ } => StatusCode::FORBIDDEN,
                 RequestError::UnsupportedMethod { method: _ } => StatusCode::METHOD_NOT_ALLOWED,
+                RequestError::Storage {
+                    source: PersistentError::Transport { reason: _ },
+                } => StatusCode::BAD_GATEWAY,
                 RequestError::PresignConfigFailure
                 | RequestError::PresignFailure { object_key: _ }
+                | RequestError::Storage { source: _ }
                 | RequestError::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
             },
+            content_range.map(|content_range| [(CONTENT_RANGE, content_range)]),
             format!("{}", &self),
         )
             .into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsatisfiable_ranges_are_passed_through() {
+        let response = RequestError::from(PersistentError::RangeNotSatisfiable {
+            object_key: "nixos-1.tar.xz".to_owned(),
+            content_range: Some("bytes */1234".to_owned()),
+        })
+        .into_response();
+
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */1234");
+    }
+}
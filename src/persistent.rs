@@ -1,21 +1,22 @@
-//! This module tries to abstract the persistent storage backend. The
-//! abstraction is not perfect as S3 leaks through pretty heavily. :)
+//! This module implements the channel logic on top of a
+//! [`Storage`] backend.
 
 use std::{
+    cell::RefCell,
     collections::{BTreeMap, BTreeSet},
     {path::Path, time::Duration},
 };
 
-use anyhow::{anyhow, Context, Result};
-use aws_sdk_s3::primitives::ByteStream;
-use axum::{
-    body::Bytes,
-    http::{self, Method},
-};
-use serde::{Deserialize, Serialize};
-use tracing::{debug, error, info};
+use axum::{body::Bytes, http};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use tracing::{debug, error, info, warn};
 
-use crate::error::RequestError;
+use crate::{
+    error::{PersistentError, RequestError},
+    storage::{Delivery, LocalStorage, ObjectStream, Precondition, S3Options, S3Storage, Storage},
+};
 
 /// The persistent configuration that lives in the S3 bucket as
 /// /channels.json.
@@ -25,13 +26,175 @@ struct PersistentChannelsConfig {
     /// corresponding <channel>.json file for configuration in the
     /// bucket.
     channels: Vec<String>,
+
+    /// Alternative names for channels, e.g. "stable" for
+    /// "nixos-25.05".
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    aliases: BTreeMap<String, String>,
+}
+
+/// An element of a channel, i.e. one published tarball.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelEntry {
+    /// The object key of the tarball without the file extension.
+    pub name: String,
+
+    /// When this element became the latest one. This and all other
+    /// metadata is unknown for elements that were published before we
+    /// recorded it.
+    #[serde(
+        default,
+        with = "time::serde::rfc3339::option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub published: Option<OffsetDateTime>,
+
+    /// Who published this element.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uploader: Option<String>,
+
+    /// The size of the tarball in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+
+    /// The SHA-256 hash of the tarball as hex string.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+
+    /// The git revision the tarball was built from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_revision: Option<String>,
+
+    /// The number of commits up to the git revision.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision_count: Option<u64>,
+
+    /// The commit time of the git revision as Unix timestamp.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<u64>,
+
+    /// Additional files that were published together with the tarball,
+    /// by file extension. Their object keys are the name of this
+    /// element with the file extension appended.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub artifacts: BTreeMap<String, Artifact>,
+}
+
+/// An additional file of a channel element.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Artifact {
+    /// The size of the file in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+
+    /// The SHA-256 hash of the file as hex string.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl ChannelEntry {
+    /// A new element without any metadata.
+    pub fn new(name: &str) -> ChannelEntry {
+        ChannelEntry {
+            name: name.to_owned(),
+            published: None,
+            uploader: None,
+            size: None,
+            sha256: None,
+            git_revision: None,
+            revision_count: None,
+            last_modified: None,
+            artifacts: BTreeMap::new(),
+        }
+    }
+
+    /// The flake attributes of this element as URL query, e.g.
+    /// `?rev=abc&revCount=12`. Nix picks these up from the immutable
+    /// link and records them in lock files. Empty, if we know none.
+    pub fn flake_query(&self) -> String {
+        let attributes = [
+            ("rev", self.git_revision.clone()),
+            (
+                "revCount",
+                self.revision_count.map(|count| count.to_string()),
+            ),
+            (
+                "lastModified",
+                self.last_modified.map(|timestamp| timestamp.to_string()),
+            ),
+        ];
+
+        let query = attributes
+            .into_iter()
+            .filter_map(|(key, value)| Some(format!("{key}={}", value?)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        if query.is_empty() {
+            query
+        } else {
+            format!("?{query}")
+        }
+    }
+}
+
+/// Channel entries as they are stored. Old channel configurations only
+/// contain the names.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PersistentChannelEntry {
+    Name(String),
+    Record(ChannelEntry),
+}
+
+impl From<PersistentChannelEntry> for ChannelEntry {
+    fn from(entry: PersistentChannelEntry) -> Self {
+        match entry {
+            PersistentChannelEntry::Name(name) => ChannelEntry::new(&name),
+            PersistentChannelEntry::Record(entry) => entry,
+        }
+    }
+}
+
+fn deserialize_latest<'de, D>(deserializer: D) -> Result<Option<ChannelEntry>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<PersistentChannelEntry>::deserialize(deserializer)?.map(ChannelEntry::from))
+}
+
+fn deserialize_previous<'de, D>(deserializer: D) -> Result<Vec<ChannelEntry>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Vec::<PersistentChannelEntry>::deserialize(deserializer)?
+        .into_iter()
+        .map(ChannelEntry::from)
+        .collect())
+}
+
+/// What the publisher tells us about a new element of a channel.
+#[derive(Debug, Clone, Default)]
+pub struct PublishInfo {
+    /// Who publishes the element.
+    pub uploader: Option<String>,
+
+    /// The git revision the tarball was built from.
+    pub git_revision: Option<String>,
+
+    /// The number of commits up to the git revision.
+    pub revision_count: Option<u64>,
+
+    /// The commit time of the git revision as Unix timestamp.
+    pub last_modified: Option<u64>,
 }
 
 /// The persistent configuration of a single channel.
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct ChannelConfig {
     /// The latest element in the channel. If this is foo, users can download it as channel/foo.tar.gz.
-    pub latest: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_latest")]
+    pub latest: Option<ChannelEntry>,
 
     /// The file extension of the files being served. If this is set to ".iso",
     /// the files have to have the form "some-file-name.iso". Multiple periods
@@ -42,40 +205,125 @@ pub struct ChannelConfig {
     #[serde(default = "default_channel_file_extension")]
     pub file_extension: String,
 
-    /// Previous tarballs in this channel.
-    #[serde(default)]
-    pub previous: Vec<String>,
+    /// The file extensions of additional files that can be published
+    /// together with the main one, e.g. [".iso", ".sha256"].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_file_extensions: Vec<String>,
+
+    /// Previous tarballs in this channel, oldest first.
+    #[serde(default, deserialize_with = "deserialize_previous")]
+    pub previous: Vec<ChannelEntry>,
+
+    /// How long presigned URLs for this channel stay valid. Large files
+    /// on slow links may need more than the server-wide default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presign_expiry_seconds: Option<u64>,
+
+    /// Whether the server streams the files of this channel itself
+    /// instead of redirecting clients to the storage backend. If this is
+    /// not set, the server-wide setting applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<bool>,
+
+    /// The binary cache that nix-channel users of this channel should
+    /// use, e.g. https://cache.nixos.org.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub binary_cache_url: Option<String>,
 }
 
-/// Removes duplicate entries from a vector.
+/// Removes entries with duplicate keys from a vector.
 ///
-/// We keep the first unique entry. All duplicate ones are removed. This
-/// function retains the order of the remaining elements.
-fn remove_duplicates<T>(vec: &mut Vec<T>) -> bool
+/// We keep the first entry for each key. All later ones are removed.
+/// This function retains the order of the remaining elements.
+fn remove_duplicates_by_key<T, K>(vec: &mut Vec<T>, key: impl Fn(&T) -> K) -> bool
 where
-    T: Eq + Ord + Clone,
+    K: Ord,
 {
     let original_size = vec.len();
     let mut seen = BTreeSet::new();
-    vec.retain(|item| seen.insert(item.clone()));
+    vec.retain(|item| seen.insert(key(item)));
 
     original_size != vec.len()
 }
 
 impl ChannelConfig {
-    pub fn init(file_extension: &str) -> ChannelConfig {
+    pub fn init(file_extension: &str, extra_file_extensions: &[String]) -> ChannelConfig {
         ChannelConfig {
             file_extension: file_extension.to_owned(),
+            extra_file_extensions: extra_file_extensions.to_vec(),
             ..Default::default()
         }
     }
 
+    /// All file extensions of this channel, the main one first.
+    pub fn file_extensions(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.file_extension.as_str())
+            .chain(self.extra_file_extensions.iter().map(String::as_str))
+    }
+
+    /// Check whether an element of this channel has a file with the
+    /// given extension.
+    pub fn provides(&self, entry: &ChannelEntry, file_extension: &str) -> bool {
+        file_extension == self.file_extension || entry.artifacts.contains_key(file_extension)
+    }
+
     /// Remove duplicates in `previous`. This happened for old releases, because
     /// we didn't prevent re-uploading the same file.
     ///
     /// Returns true, if we removed entries.
     pub fn remove_previous_duplicates(&mut self) -> bool {
-        remove_duplicates(&mut self.previous)
+        remove_duplicates_by_key(&mut self.previous, |entry| entry.name.clone())
+    }
+
+    /// The name of the latest element, if there is one.
+    pub fn latest_name(&self) -> Option<&str> {
+        self.latest.as_ref().map(|entry| entry.name.as_str())
+    }
+
+    /// All elements of the channel, newest first.
+    pub fn history(&self) -> impl Iterator<Item = &ChannelEntry> {
+        self.latest.iter().chain(self.previous.iter().rev())
+    }
+
+    /// The channel-specific expiry of presigned URLs, if there is one.
+    pub fn presign_expiry(&self) -> Option<Duration> {
+        self.presign_expiry_seconds.map(Duration::from_secs)
+    }
+
+    /// Check whether the object key belongs to this channel, i.e. is
+    /// a file of the latest or a previous element.
+    pub fn contains_object(&self, object_key: &str) -> bool {
+        self.file_extensions().any(|file_extension| {
+            object_key
+                .strip_suffix(file_extension)
+                .is_some_and(|basename| {
+                    self.history()
+                        .any(|entry| entry.name == basename && self.provides(entry, file_extension))
+                })
+        })
+    }
+
+    /// Look up an element by its position in the channel history. Zero
+    /// is the latest element, one the element before it, and so on.
+    pub fn nth_latest(&self, index: usize) -> Option<&ChannelEntry> {
+        self.history().nth(index)
+    }
+
+    /// Find the history index of the element that was the latest one at
+    /// the given time.
+    ///
+    /// We give up when we reach elements without a publish time, because
+    /// we cannot tell whether they were published before or after.
+    pub fn index_at(&self, at: OffsetDateTime) -> Option<usize> {
+        for (index, entry) in self.history().enumerate() {
+            match entry.published {
+                Some(published) if published <= at => return Some(index),
+                Some(_) => continue,
+                None => return None,
+            }
+        }
+
+        None
     }
 }
 
@@ -88,6 +336,9 @@ fn default_channel_file_extension() -> String {
 pub struct ChannelsConfig {
     /// A mapping from channel name to latest object key.
     channels: BTreeMap<String, ChannelConfig>,
+
+    /// A mapping from alias to channel name.
+    aliases: BTreeMap<String, String>,
 }
 
 impl ChannelsConfig {
@@ -98,71 +349,179 @@ impl ChannelsConfig {
     pub fn channel(&self, channel_name: &str) -> Option<ChannelConfig> {
         self.channels.get(channel_name).cloned()
     }
+
+    /// All aliases and the channels they point to.
+    pub fn aliases(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.aliases.iter().map(|(k, v)| (k.as_ref(), v.as_ref()))
+    }
+
+    /// Turn an alias into the name of the channel it points to. Other
+    /// names are returned unchanged.
+    pub fn resolve_alias<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases.get(name).map_or(name, String::as_str)
+    }
+
+    /// Find the channel for a file name below /channel. The file name
+    /// is either `{channel}{extension}` for the latest element, or
+    /// `{channel}~{n}{extension}` for the n-th element before it.
+    /// Instead of the channel name, an alias can be used. The extension
+    /// is any of the file extensions of the channel.
+    ///
+    /// Returns the channel name, its configuration, the history index
+    /// and the file extension.
+    pub fn channel_for_file_name(
+        &self,
+        file_name: &str,
+    ) -> Option<(&str, &ChannelConfig, usize, &str)> {
+        let aliases = self.aliases().filter_map(|(alias, channel_name)| {
+            let (channel_name, channel) = self.channels.get_key_value(channel_name)?;
+            Some((alias, channel_name.as_str(), channel))
+        });
+
+        // We cannot split off the extension first, because channel
+        // names often include periods, such as foobar-24.05.
+        self.channels()
+            .map(|(channel_name, channel)| (channel_name, channel_name, channel))
+            .chain(aliases)
+            .find_map(|(name, channel_name, channel)| {
+                let rest = file_name.strip_prefix(name)?;
+
+                channel.file_extensions().find_map(|file_extension| {
+                    let stem = rest.strip_suffix(file_extension)?;
+
+                    let index = match stem.strip_prefix('~') {
+                        None if stem.is_empty() => 0,
+                        Some(index) if index.bytes().all(|b| b.is_ascii_digit()) => {
+                            index.parse().ok()?
+                        }
+                        _ => return None,
+                    };
+
+                    Some((channel_name, channel, index, file_extension))
+                })
+            })
+    }
+
+    /// Find the channel an object key belongs to.
+    pub fn channel_for_object(&self, object_key: &str) -> Option<(&str, &ChannelConfig)> {
+        self.channels()
+            .find(|(_, channel)| channel.contains_object(object_key))
+    }
 }
 
+/// How long presigned URLs stay valid, unless configured otherwise.
+pub const DEFAULT_PRESIGN_EXPIRY: Duration = Duration::from_secs(600);
+
+/// S3 refuses to presign URLs that stay valid for longer than a week.
+pub const MAX_PRESIGN_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How often we try to update a channel that is concurrently modified by
+/// someone else.
+const UPDATE_ATTEMPTS: usize = 5;
+
 pub struct Client {
-    client: aws_sdk_s3::Client,
-    bucket: String,
+    storage: Box<dyn Storage>,
+
+    /// All object keys live below this prefix. This is either empty or
+    /// ends with a slash.
+    prefix: String,
+
+    /// How long presigned URLs stay valid, if the channel doesn't say
+    /// otherwise.
+    presign_expiry: Duration,
 }
 
 impl Client {
+    /// Use the given storage backend.
+    pub fn new(storage: impl Storage + 'static) -> Client {
+        Self {
+            storage: Box::new(storage),
+            prefix: String::new(),
+            presign_expiry: DEFAULT_PRESIGN_EXPIRY,
+        }
+    }
+
+    /// Change how long presigned URLs stay valid by default.
+    pub fn with_presign_expiry(mut self, presign_expiry: Duration) -> Client {
+        self.presign_expiry = presign_expiry;
+        self
+    }
+
+    /// Place all metadata and tarballs below the given key prefix. This
+    /// allows to share a bucket with other users.
+    pub fn with_prefix(mut self, prefix: &str) -> Client {
+        let prefix = prefix.trim_matches('/');
+
+        self.prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{prefix}/")
+        };
+        self
+    }
+
+    /// Turn a key relative to the prefix into the key in the storage
+    /// backend.
+    fn key(&self, object_key: &str) -> String {
+        format!("{}{object_key}", self.prefix)
+    }
+
     /// Open an S3 client with configuration from the environment.
-    // TODO Return a custom error type.
-    pub async fn new_from_env(bucket: &str) -> Result<Client> {
-        let amzn_config = aws_config::load_from_env().await;
-        let s3_config = aws_sdk_s3::config::Builder::from(&amzn_config)
-            // TODO For minio compat. Should this be configurable?
-            .force_path_style(true)
-            .build();
-
-        Ok(Self {
-            client: aws_sdk_s3::Client::from_conf(s3_config),
-            bucket: bucket.to_owned(),
-        })
+    pub async fn new_from_env(bucket: &str) -> Result<Client, PersistentError> {
+        Ok(Self::new(S3Storage::new_from_env(bucket).await?))
     }
 
-    /// Read a file from S3 into memory. This should only be used for
-    /// small files.
-    // TODO Return a custom error type.
-    async fn read_file(&self, object_key: &str) -> Result<Bytes> {
-        let response = self
-            .client
-            .get_object()
-            .bucket(&self.bucket)
-            .key(object_key)
-            .send()
-            .await
-            // TODO Better error.
-            .with_context(|| format!("Failed to read: {object_key}"))?;
+    /// Open an S3 client with explicit connection options.
+    pub async fn new_s3(bucket: &str, options: S3Options) -> Result<Client, PersistentError> {
+        Ok(Self::new(S3Storage::new(bucket, options).await?))
+    }
 
-        Ok(response.body.collect().await?.into_bytes())
+    /// Use a local directory as storage.
+    pub fn new_local(root: &Path) -> Result<Client, PersistentError> {
+        Ok(Self::new(LocalStorage::new(root)?))
     }
 
-    // TODO Return a custom error type.
-    pub async fn load_channels_config(&self) -> Result<ChannelsConfig> {
+    pub async fn load_channels_config(&self) -> Result<ChannelsConfig, PersistentError> {
+        let index_file = self.key("channels.json");
         let persistent_config: PersistentChannelsConfig =
-            serde_json::from_slice(&self.read_file("channels.json").await?)
-                .context("Failed to deserialize channels.json")?;
+            from_json(&index_file, &self.storage.read(&index_file).await?)?;
 
         debug!("Loaded channel config: {persistent_config:?}");
 
-        let mut channels_config = ChannelsConfig::default();
+        let mut channels_config = ChannelsConfig {
+            aliases: persistent_config.aliases,
+            ..Default::default()
+        };
 
         for channel_name in persistent_config.channels {
-            let config_file = format!("{channel_name}.json");
-            let channel_config = self
-                .read_file(&config_file)
-                .await
-                .context("Failed to read channel config")
-                .and_then(|bytes| {
-                    serde_json::from_slice::<ChannelConfig>(&bytes)
-                        .context("Failed to deserialize channel configuration")
-                });
+            let config_file = self.key(&format!("{channel_name}.json"));
+            let channel_config = match self.storage.read(&config_file).await {
+                Ok(bytes) => from_json::<ChannelConfig>(&config_file, &bytes),
+                Err(err) => Err(err),
+            };
             match channel_config {
-                Ok(channel_config) => {
+                Ok(mut channel_config) => {
+                    // Otherwise, every request for this channel fails.
+                    if channel_config
+                        .presign_expiry()
+                        .is_some_and(|expiry| expiry > MAX_PRESIGN_EXPIRY)
+                    {
+                        warn!(
+                            "Channel {channel_name} has a presign expiry above the maximum of {} seconds. Using the maximum.",
+                            MAX_PRESIGN_EXPIRY.as_secs()
+                        );
+                        channel_config.presign_expiry_seconds = Some(MAX_PRESIGN_EXPIRY.as_secs());
+                    }
+
+                    // URLs that expire immediately are useless.
+                    if channel_config.presign_expiry() == Some(Duration::ZERO) {
+                        warn!("Channel {channel_name} has a presign expiry of zero. Using the server default.");
+                        channel_config.presign_expiry_seconds = None;
+                    }
+
                     info!(
                         "Channel {channel_name} points to: {}",
-                        channel_config.latest.as_deref().unwrap_or("(nothing yet)")
+                        channel_config.latest_name().unwrap_or("(nothing yet)")
                     );
                     channels_config
                         .channels
@@ -170,8 +529,7 @@ impl Client {
                 }
                 Err(err) => {
                     error!("Failed reading configuration for {channel_name:?}, with path {config_file:?} in the bucket. Ignoring.");
-                    info!("Context: {}", err);
-                    info!("Cause: {}", err.root_cause());
+                    info!("Cause: {}", err);
                     continue;
                 }
             }
@@ -181,238 +539,1315 @@ impl Client {
     }
 
     /// Return a signed request for a specific object key in the bucket.
+    ///
+    /// Without an explicit expiry, the signature is valid for the
+    /// configured default duration.
     pub async fn sign_request(
         &self,
         method: http::Method,
         object_key: &str,
+        expires_in: Option<Duration>,
     ) -> Result<String, RequestError> {
-        use aws_sdk_s3::presigning::PresigningConfig;
-
-        // TODO Should expiration be configurable?
-        let presigning_config = PresigningConfig::expires_in(Duration::from_secs(600))
-            .map_err(|_e| RequestError::PresignConfigFailure)?;
-
-        let req = match method {
-            Method::GET => self
-                .client
-                .get_object()
-                .bucket(&self.bucket)
-                .key(object_key)
-                .presigned(presigning_config)
-                .await
-                .map_err(|_e| RequestError::PresignFailure {
-                    object_key: object_key.to_owned(),
-                }),
-            Method::HEAD => self
-                .client
-                .head_object()
-                .bucket(&self.bucket)
-                .key(object_key)
-                .presigned(presigning_config)
-                .await
-                .map_err(|_e| RequestError::PresignFailure {
-                    object_key: object_key.to_owned(),
-                }),
-            unsupported => Err(RequestError::UnsupportedMethod {
-                method: unsupported,
-            }),
-        }?;
-
-        Ok(req.uri().to_owned())
-    }
-
-    /// Upload a file to the persistent store. Doesn't update any channel.
-    async fn write_file(&self, object_key: &str, file: &Path) -> Result<()> {
-        // We would want to stream the file and not load it all in
-        // memory, but it results in XAmzContentSHA256Mismatch. :(
-        let data = tokio::fs::read(file)
+        self.storage
+            .presign(
+                method,
+                &self.key(object_key),
+                expires_in.unwrap_or(self.presign_expiry),
+            )
             .await
-            .context("Failed to read input file")?;
-
-        self.client
-            .put_object()
-            .bucket(&self.bucket)
-            .key(object_key)
-            .body(data.into())
-            .send()
+    }
+
+    /// Decide how a client gets the content of a specific object key.
+    /// The expiry works as for [`Client::sign_request`]. With `proxy`,
+    /// we prefer streaming the object over redirecting to it.
+    pub async fn deliver(
+        &self,
+        method: http::Method,
+        object_key: &str,
+        expires_in: Option<Duration>,
+        proxy: bool,
+    ) -> Result<Delivery, RequestError> {
+        self.storage
+            .deliver(
+                method,
+                &self.key(object_key),
+                expires_in.unwrap_or(self.presign_expiry),
+                proxy,
+            )
             .await
-            .with_context(|| format!("Failed to upload file: {}", file.display()))?;
+    }
 
-        Ok(())
+    /// Open an object to stream it to a client. See
+    /// [`Storage::read_stream`].
+    pub async fn read_stream(
+        &self,
+        object_key: &str,
+        range: Option<&str>,
+    ) -> Result<ObjectStream, PersistentError> {
+        self.storage.read_stream(&self.key(object_key), range).await
     }
 
-    async fn write_data(&self, object_key: &str, data: Vec<u8>) -> Result<()> {
-        let data = ByteStream::from(data.to_owned());
+    /// Add a channel to the configuration, and seed with stub json config.
+    ///
+    /// Either both the channel file and its entry in channels.json are
+    /// written or neither.
+    pub async fn add_channel(
+        &self,
+        channel_name: &str,
+        file_extension: &str,
+        extra_file_extensions: &[String],
+    ) -> Result<(), PersistentError> {
+        // Slashes would clash with the nix-channel layout below
+        // /channel/{channel}/ and tildes with older releases as
+        // /channel/{channel}~{n}.
+        if channel_name == "channels"
+            || channel_name.is_empty()
+            || channel_name.contains(['/', '~'])
+        {
+            return Err(invalid_input(&format!(
+                "Invalid channel name: {channel_name}"
+            )));
+        }
+
+        let config_file = self.key(&format!("{channel_name}.json"));
 
-        self.client
-            .put_object()
-            .bucket(&self.bucket)
-            .key(object_key)
-            .body(data)
-            .send()
-            .await
-            .context("Failed to upload file")?;
+        // This only succeeds, if the channel file doesn't exist yet. So
+        // concurrent runs can't overwrite each other's channel.
+        if !self
+            .storage
+            .write_if(
+                &config_file,
+                to_json(
+                    &config_file,
+                    &ChannelConfig::init(file_extension, extra_file_extensions),
+                )?,
+                Precondition::Absent,
+            )
+            .await?
+        {
+            return Err(PersistentError::AlreadyExists {
+                object_key: config_file,
+            });
+        }
+
+        if let Err(err) = self.add_to_channels_index(channel_name).await {
+            // Nobody knows about the channel yet, so it's safe to remove
+            // it again.
+            if let Err(delete_err) = self.storage.delete(&config_file).await {
+                error!("Failed to remove {config_file:?} again ({delete_err}). It was leaked to the bucket.");
+            }
+
+            return Err(err);
+        }
 
         Ok(())
     }
 
-    async fn file_exists(&self, object_key: &str) -> Result<bool> {
-        match self
-            .client
-            .head_object()
-            .bucket(&self.bucket)
-            .key(object_key)
-            .send()
-            .await
-        {
-            Ok(_) => Ok(true),
-            Err(err) => {
-                if err.as_service_error().map(|e| e.is_not_found()) == Some(true) {
-                    Ok(false)
-                } else {
-                    Err(anyhow!("Failed to check if object exists: {err}"))
-                }
+    /// Add a channel to channels.json.
+    async fn add_to_channels_index(&self, channel_name: &str) -> Result<(), PersistentError> {
+        self.modify_channels_index(|persistent_config| {
+            if persistent_config.aliases.contains_key(channel_name) {
+                return Err(invalid_input(&format!(
+                    "There is already an alias called {channel_name}"
+                )));
             }
-        }
+
+            if persistent_config.channels.iter().any(|c| c == channel_name) {
+                return Ok(false);
+            }
+
+            persistent_config.channels.push(channel_name.into());
+            Ok(true)
+        })
+        .await
     }
 
-    /// Add a channel to the configuration, and seed with stub json config.
-    pub async fn add_channel(&self, channel_name: &str, file_extension: &str) -> Result<()> {
-        if channel_name == "channels" {
-            return Err(anyhow!("Invalid channel name: {channel_name}"));
-        }
+    /// Point an alias to a channel, or remove it, if no channel is
+    /// given.
+    pub async fn set_alias(
+        &self,
+        alias: &str,
+        channel_name: Option<&str>,
+    ) -> Result<(), PersistentError> {
+        self.modify_channels_index(|persistent_config| {
+            let Some(channel_name) = channel_name else {
+                return Ok(persistent_config.aliases.remove(alias).is_some());
+            };
 
-        if self.file_exists(&format!("{channel_name}.json")).await? {
-            return Err(anyhow!("Refusing to overwrite channel: {channel_name}"));
-        }
+            if alias.is_empty() || alias.contains(['/', '~']) {
+                return Err(invalid_input(&format!("Invalid alias: {alias}")));
+            }
+            if persistent_config.channels.iter().any(|c| c == alias) {
+                return Err(invalid_input(&format!(
+                    "There is already a channel called {alias}"
+                )));
+            }
+            if !persistent_config.channels.iter().any(|c| c == channel_name) {
+                return Err(PersistentError::NotFound {
+                    object_key: self.key(&format!("{channel_name}.json")),
+                });
+            }
 
-        self.write_data(
-            &format!("{channel_name}.json"),
-            serde_json::to_vec_pretty(&ChannelConfig::init(file_extension))
-                .context("Failed to serialize channel")?,
-        )
+            Ok(persistent_config
+                .aliases
+                .insert(alias.to_owned(), channel_name.to_owned())
+                .as_deref()
+                != Some(channel_name))
+        })
         .await
-        .context("Failed to create channel.")?;
+    }
 
-        // Add channel to channels config.
-        let mut persistent_config = if self.file_exists("channels.json").await? {
-            serde_json::from_slice(&self.read_file("channels.json").await?)
-                .context("Failed to deserialize channels.json")?
-        } else {
-            PersistentChannelsConfig::default()
-        };
+    /// Apply a change to channels.json. The change returns whether it
+    /// modified anything. Concurrent modifications are detected and
+    /// retried like in [`Client::update_channel`].
+    async fn modify_channels_index(
+        &self,
+        change: impl Fn(&mut PersistentChannelsConfig) -> Result<bool, PersistentError>,
+    ) -> Result<(), PersistentError> {
+        let index_file = self.key("channels.json");
 
-        persistent_config.channels.push(channel_name.into());
+        for attempt in 1..=UPDATE_ATTEMPTS {
+            let (mut persistent_config, precondition) =
+                match self.storage.read_versioned(&index_file).await {
+                    Ok((data, version)) => (
+                        from_json::<PersistentChannelsConfig>(&index_file, &data)?,
+                        Precondition::Version(version),
+                    ),
+                    Err(PersistentError::NotFound { object_key: _ }) => {
+                        (PersistentChannelsConfig::default(), Precondition::Absent)
+                    }
+                    Err(err) => return Err(err),
+                };
 
-        self.write_data(
-            "channels.json",
-            serde_json::to_vec_pretty(&persistent_config).context("Failed to serialize channel")?,
-        )
-        .await.context(format!("Failed to write channels information. {channel_name}.json file was leaked to the bucket."))?;
+            if !change(&mut persistent_config)? {
+                return Ok(());
+            }
 
-        Ok(())
+            if self
+                .storage
+                .write_if(
+                    &index_file,
+                    to_json(&index_file, &persistent_config)?,
+                    precondition,
+                )
+                .await?
+            {
+                return Ok(());
+            }
+
+            warn!("channels.json was modified concurrently (attempt {attempt}/{UPDATE_ATTEMPTS}).");
+        }
+
+        Err(PersistentError::Conflict {
+            object_key: index_file,
+        })
     }
 
-    /// Update the channel to point to the given file.
+    /// Update the channel to point to the given files. There must be
+    /// one file with the main file extension of the channel and at most
+    /// one for each extra file extension. All files need the same name
+    /// apart from the extension.
+    ///
+    /// We record the size and hash of the files along with what the
+    /// publisher tells us about them.
     ///
-    /// **Note:** This operation is not concurrency-safe! Clients must
-    /// serialize update operations.
-    pub async fn update_channel(&self, channel_name: &str, file: &Path) -> Result<()> {
+    /// Concurrent updates of the same channel are detected via
+    /// conditional writes. We retry a couple of times, before we give up.
+    pub async fn update_channel(
+        &self,
+        channel_name: &str,
+        files: &[&Path],
+        info: &PublishInfo,
+    ) -> Result<(), PersistentError> {
         let channels_config = self.load_channels_config().await?;
-        let mut channel = channels_config
-            .channel(channel_name)
-            .ok_or_else(|| anyhow!("Channel {channel_name} does not exit!"))?;
-
-        // We're changing the channel config anyhow, so let's clean it up at the
-        // same time.
-        if channel.remove_previous_duplicates() {
-            info!("Cleaned up duplicate entries in the channel history.")
-        }
-
-        // Path::ends_with and Path::extension unfortunately don't do
-        // what we need.
-        if !file
-            .as_os_str()
-            .to_str()
-            .ok_or_else(|| anyhow!("File name is not valid UTF-8"))?
-            .ends_with(&channel.file_extension)
+        let channel_config =
+            channels_config
+                .channel(channel_name)
+                .ok_or_else(|| PersistentError::NotFound {
+                    object_key: self.key(&format!("{channel_name}.json")),
+                })?;
+
+        let mut basename = None;
+        let mut object_keys = BTreeMap::new();
+
+        for &file in files {
+            let object_key = file
+                .file_name()
+                .ok_or_else(|| invalid_input(&format!("No file name: {}", file.display())))?
+                .to_str()
+                .ok_or_else(|| {
+                    invalid_input(&format!(
+                        "File name needs to be valid UTF-8: {}",
+                        file.display()
+                    ))
+                })?
+                .to_owned();
+
+            // Path::ends_with and Path::extension unfortunately don't do
+            // what we need. If extensions overlap, such as .xz and
+            // .tar.xz, the longest one wins.
+            let file_extension = channel_config
+                .file_extensions()
+                .filter(|file_extension| object_key.ends_with(file_extension))
+                .max_by_key(|file_extension| file_extension.len())
+                .ok_or_else(|| {
+                    invalid_input(&format!(
+                        "Invalid file ending. Only {} is supported: {}",
+                        channel_config
+                            .file_extensions()
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        file.display()
+                    ))
+                })?;
+
+            // This unwrap is safe, because we checked the suffix above.
+            let file_basename = object_key.strip_suffix(file_extension).unwrap();
+            if *basename.get_or_insert(file_basename.to_owned()) != file_basename {
+                return Err(invalid_input(
+                    "All files need the same name apart from the extension",
+                ));
+            }
+
+            if object_keys
+                .insert(file_extension.to_owned(), (object_key.clone(), file))
+                .is_some()
+            {
+                return Err(invalid_input(&format!(
+                    "More than one file with extension {file_extension}"
+                )));
+            }
+
+            // This is only a shortcut to avoid needless uploads. The
+            // conditional writes below make sure we don't overwrite
+            // anything.
+            if self.storage.exists(&self.key(&object_key)).await? {
+                return Err(PersistentError::AlreadyExists {
+                    object_key: self.key(&object_key),
+                });
+            }
+        }
+
+        let (Some(basename), Some((object_key, main_file))) =
+            (basename, object_keys.remove(&channel_config.file_extension))
+        else {
+            return Err(invalid_input(&format!(
+                "A file with extension {} is required",
+                channel_config.file_extension
+            )));
+        };
+
+        // Nix takes the revision from the immutable URL and expects a
+        // full SHA-1 hash. Abbreviated revisions would break every fetch
+        // of this element for good.
+        if info
+            .git_revision
+            .as_ref()
+            .is_some_and(|rev| rev.len() != 40 || !rev.bytes().all(|b| b.is_ascii_hexdigit()))
         {
-            return Err(anyhow!(
-                "Invalid file ending. Only {} is supported: {}",
-                channel.file_extension,
-                file.display()
+            return Err(invalid_input(
+                "The git revision must be a full hash with 40 hex digits",
             ));
         }
 
-        let object_key = file
-            .file_name()
-            .ok_or_else(|| anyhow!("No file name: {}", file.display()))?
-            .to_str()
-            .ok_or_else(|| anyhow!("File name needs to be valid UTF-8: {}", file.display()))?
-            .to_owned();
+        let (size, sha256) = hash_file(main_file).await?;
+        let mut entry = ChannelEntry {
+            uploader: info.uploader.clone(),
+            size: Some(size),
+            sha256: Some(sha256),
+            git_revision: info.git_revision.clone(),
+            revision_count: info.revision_count,
+            last_modified: info.last_modified,
+            ..ChannelEntry::new(&basename)
+        };
 
-        if self.file_exists(&object_key).await? {
-            return Err(anyhow!("Refusing to overwrite key: {object_key}"));
+        for (file_extension, (_, file)) in &object_keys {
+            let (size, sha256) = hash_file(file).await?;
+            entry.artifacts.insert(
+                file_extension.clone(),
+                Artifact {
+                    size: Some(size),
+                    sha256: Some(sha256),
+                },
+            );
         }
 
-        let basename = object_key
-            .strip_suffix(&channel.file_extension)
-            // This unwrap is safe, because we checked the suffix earlier.
-            .unwrap()
-            .to_owned();
+        // The main file goes last, so there is nothing to serve before
+        // all files are there.
+        let uploads = object_keys
+            .values()
+            .map(|(object_key, file)| (object_key.as_str(), *file))
+            .chain(std::iter::once((object_key.as_str(), main_file)));
+        self.upload_release(uploads).await?;
 
-        self.write_file(&object_key, file).await?;
+        let result = self.set_latest(channel_name, entry, &object_key).await;
 
-        println!(
-            "Updating channel {channel_name} from {} to {}.",
-            channel.latest.as_deref().unwrap_or("(nothing)"),
-            object_key
-        );
+        if result.is_err() {
+            error!("Failed to update channel {channel_name}. This leaked the files of {basename}! Remove them manually, if this is an issue.");
+        }
+
+        result
+    }
+
+    /// Upload the files of a release. Files of existing objects are
+    /// never overwritten. If someone else publishes a release with the
+    /// same name concurrently, we remove what we uploaded so far and fail.
+    async fn upload_release(
+        &self,
+        files: impl Iterator<Item = (&str, &Path)>,
+    ) -> Result<(), PersistentError> {
+        let mut uploaded: Vec<String> = Vec::new();
+
+        for (object_key, file) in files {
+            let object_key = self.key(object_key);
+
+            if !self
+                .storage
+                .write_file_if(&object_key, file, Precondition::Absent)
+                .await?
+            {
+                for uploaded_key in uploaded {
+                    if let Err(err) = self.storage.delete(&uploaded_key).await {
+                        error!("Failed to remove {uploaded_key:?} again ({err}). It was leaked to the bucket.");
+                    }
+                }
+
+                return Err(PersistentError::AlreadyExists { object_key });
+            }
 
-        if let Some(previous) = channel.latest.take() {
-            channel.previous.push(previous);
+            uploaded.push(object_key);
         }
-        channel.latest = Some(basename);
 
-        self.write_data(
-            &format!("{channel_name}.json"),
-            serde_json::to_vec_pretty(&channel).context("Failed to serialize channel")?,
-        )
-        .await.context("Failed to update channel. This leaked the tarball! Remove it manually, if this is an issue.")?;
+        Ok(())
+    }
+
+    /// Point the channel to a new latest element, retrying if the channel
+    /// is modified concurrently.
+    async fn set_latest(
+        &self,
+        channel_name: &str,
+        entry: ChannelEntry,
+        object_key: &str,
+    ) -> Result<(), PersistentError> {
+        // The previous latest element as seen by the attempt that went
+        // through.
+        let previous_name = RefCell::new(None);
+
+        self.modify_channel(channel_name, |channel| {
+            // Each release name appears only once in the history.
+            if channel
+                .history()
+                .any(|existing| existing.name == entry.name)
+            {
+                return Err(PersistentError::AlreadyExists {
+                    object_key: self.key(object_key),
+                });
+            }
+
+            // We're changing the channel config anyhow, so let's clean it up at the
+            // same time.
+            if channel.remove_previous_duplicates() {
+                info!("Cleaned up duplicate entries in the channel history.")
+            }
+
+            previous_name.replace(channel.latest_name().map(str::to_owned));
+
+            if let Some(previous) = channel.latest.take() {
+                channel.previous.push(previous);
+            }
+            channel.latest = Some(ChannelEntry {
+                published: Some(OffsetDateTime::now_utc()),
+                ..entry.clone()
+            });
+
+            Ok(true)
+        })
+        .await?;
+
+        info!(
+            "Updated channel {channel_name} from {} to {object_key}.",
+            previous_name.into_inner().as_deref().unwrap_or("(nothing)")
+        );
 
         Ok(())
     }
+
+    /// Allow files with additional extensions in future releases of a
+    /// channel. Extensions the channel already has are ignored.
+    pub async fn add_file_extensions(
+        &self,
+        channel_name: &str,
+        file_extensions: &[String],
+    ) -> Result<(), PersistentError> {
+        if let Some(invalid) = file_extensions
+            .iter()
+            .find(|ext| ext.is_empty() || ext.contains('/'))
+        {
+            return Err(invalid_input(&format!(
+                "Invalid file extension: {invalid:?}"
+            )));
+        }
+
+        self.modify_channel(channel_name, |channel| {
+            let mut changed = false;
+
+            for file_extension in file_extensions {
+                if !channel.file_extensions().any(|ext| ext == file_extension) {
+                    channel.extra_file_extensions.push(file_extension.clone());
+                    changed = true;
+                }
+            }
+
+            Ok(changed)
+        })
+        .await
+    }
+
+    /// Apply a change to the configuration of a channel. The change
+    /// returns whether it modified anything. Concurrent modifications
+    /// are detected via conditional writes and retried.
+    async fn modify_channel(
+        &self,
+        channel_name: &str,
+        change: impl Fn(&mut ChannelConfig) -> Result<bool, PersistentError>,
+    ) -> Result<(), PersistentError> {
+        let config_file = self.key(&format!("{channel_name}.json"));
+
+        for attempt in 1..=UPDATE_ATTEMPTS {
+            let (data, version) = self.storage.read_versioned(&config_file).await?;
+            let mut channel = from_json::<ChannelConfig>(&config_file, &data)?;
+
+            if !change(&mut channel)? {
+                return Ok(());
+            }
+
+            // This only succeeds, if nobody changed the channel since we
+            // read it. Otherwise, we start over with the new state.
+            if self
+                .storage
+                .write_if(
+                    &config_file,
+                    to_json(&config_file, &channel)?,
+                    Precondition::Version(version),
+                )
+                .await?
+            {
+                return Ok(());
+            }
+
+            warn!("Channel {channel_name} was modified concurrently (attempt {attempt}/{UPDATE_ATTEMPTS}).");
+        }
+
+        Err(PersistentError::Conflict {
+            object_key: config_file,
+        })
+    }
+}
+
+/// Compute the size and SHA-256 hash of a file.
+async fn hash_file(file: &Path) -> Result<(u64, String), PersistentError> {
+    use tokio::io::AsyncReadExt;
+
+    let io_error = |source| PersistentError::Io {
+        path: file.to_owned(),
+        source,
+    };
+
+    let mut reader = tokio::fs::File::open(file).await.map_err(io_error)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    let mut size = 0;
+
+    loop {
+        let read = reader.read(&mut buffer).await.map_err(io_error)?;
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+fn invalid_input(reason: &str) -> PersistentError {
+    PersistentError::InvalidInput {
+        reason: reason.to_owned(),
+    }
+}
+
+fn from_json<T: DeserializeOwned>(object_key: &str, data: &[u8]) -> Result<T, PersistentError> {
+    serde_json::from_slice(data).map_err(|source| PersistentError::Deserialization {
+        object_key: object_key.to_owned(),
+        source,
+    })
+}
+
+fn to_json<T: Serialize>(object_key: &str, value: &T) -> Result<Bytes, PersistentError> {
+    Ok(serde_json::to_vec_pretty(value)
+        .map_err(|source| PersistentError::Serialization {
+            object_key: object_key.to_owned(),
+            source,
+        })?
+        .into())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::MemoryStorage;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// A storage backend that simulates other publishers. Before each
+    /// of the first `conflicts` conditional updates, another publisher
+    /// sneaks in a new version. Writes to `broken` always fail.
+    struct RacyStorage {
+        inner: MemoryStorage,
+        conflicts: Arc<AtomicUsize>,
+        broken: Option<&'static str>,
+    }
+
+    impl RacyStorage {
+        fn new(conflicts: Arc<AtomicUsize>) -> RacyStorage {
+            Self {
+                inner: MemoryStorage::new(),
+                conflicts,
+                broken: None,
+            }
+        }
+
+        async fn sneak_in(&self, object_key: &str, other: String) -> Result<(), PersistentError> {
+            let data = self.inner.read(object_key).await?;
+
+            let data = if object_key == "channels.json" {
+                let mut config: PersistentChannelsConfig = from_json(object_key, &data)?;
+                config.channels.push(other);
+                to_json(object_key, &config)?
+            } else {
+                let mut channel: ChannelConfig = from_json(object_key, &data)?;
+                channel
+                    .previous
+                    .extend(channel.latest.replace(ChannelEntry::new(&other)));
+                to_json(object_key, &channel)?
+            };
+
+            self.inner.write(object_key, data).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Storage for RacyStorage {
+        async fn read(&self, object_key: &str) -> Result<Bytes, PersistentError> {
+            self.inner.read(object_key).await
+        }
+
+        async fn read_versioned(
+            &self,
+            object_key: &str,
+        ) -> Result<(Bytes, String), PersistentError> {
+            self.inner.read_versioned(object_key).await
+        }
+
+        async fn write(&self, object_key: &str, data: Bytes) -> Result<(), PersistentError> {
+            self.inner.write(object_key, data).await
+        }
+
+        async fn write_if(
+            &self,
+            object_key: &str,
+            data: Bytes,
+            precondition: Precondition,
+        ) -> Result<bool, PersistentError> {
+            if self.broken == Some(object_key) {
+                return Err(PersistentError::Transport {
+                    reason: format!("Broken: {object_key}"),
+                });
+            }
+
+            if matches!(precondition, Precondition::Version(_)) {
+                let conflict =
+                    self.conflicts
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+
+                if let Ok(remaining) = conflict {
+                    self.sneak_in(object_key, format!("other-{remaining}"))
+                        .await?;
+                }
+            }
+
+            self.inner.write_if(object_key, data, precondition).await
+        }
+
+        async fn delete(&self, object_key: &str) -> Result<(), PersistentError> {
+            self.inner.delete(object_key).await
+        }
+
+        async fn exists(&self, object_key: &str) -> Result<bool, PersistentError> {
+            self.inner.exists(object_key).await
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<String>, PersistentError> {
+            self.inner.list(prefix).await
+        }
+
+        async fn presign(
+            &self,
+            method: http::Method,
+            object_key: &str,
+            expires_in: Duration,
+        ) -> Result<String, RequestError> {
+            self.inner.presign(method, object_key, expires_in).await
+        }
+    }
+
+    fn previous_names(channel: &ChannelConfig) -> Vec<&str> {
+        channel
+            .previous
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect()
+    }
+
+    /// A temporary directory for the files of a test. It is removed
+    /// with everything in it when dropped.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(test_name: &str) -> TempDir {
+            let dir = std::env::temp_dir()
+                .join(format!("s3-nix-channel-{test_name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+
+            TempDir(dir)
+        }
+
+        /// Create a file with the given name. Its content is the name.
+        fn file(&self, file_name: &str) -> std::path::PathBuf {
+            let file = self.0.join(file_name);
+            std::fs::write(&file, file_name).unwrap();
+            file
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A full git revision.
+    const REV: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    #[tokio::test]
+    async fn publish_and_serve_works() {
+        let client = Client::new(MemoryStorage::new());
+
+        client
+            .add_channel("nixos-25.05", ".tar.xz", &[])
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.add_channel("nixos-25.05", ".tar.xz", &[]).await,
+            Err(PersistentError::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            client.add_channel("channels", ".tar.xz", &[]).await,
+            Err(PersistentError::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            client.add_channel("nixos/25.05", ".tar.xz", &[]).await,
+            Err(PersistentError::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            client.add_channel("nixos~25.05", ".tar.xz", &[]).await,
+            Err(PersistentError::InvalidInput { .. })
+        ));
+
+        let config = client.load_channels_config().await.unwrap();
+        assert_eq!(config.channel("nixos-25.05").unwrap().latest, None);
+
+        let dir = TempDir::new("publish");
+        let first = dir.file("nixos-25.05-1.tar.xz");
+        let second = dir.file("nixos-25.05-2.tar.xz");
+        let info = PublishInfo {
+            uploader: Some("alice".to_owned()),
+            git_revision: Some(REV.to_owned()),
+            revision_count: Some(42),
+            last_modified: None,
+        };
+        client
+            .update_channel("nixos-25.05", &[&first], &info)
+            .await
+            .unwrap();
+        client
+            .update_channel("nixos-25.05", &[&second], &PublishInfo::default())
+            .await
+            .unwrap();
+
+        // Only full SHA-1 revisions are accepted.
+        let third = dir.file("nixos-25.05-3.tar.xz");
+        for rev in ["0123abcd".to_owned(), REV.repeat(2)[..64].to_owned()] {
+            let bad_rev = PublishInfo {
+                git_revision: Some(rev),
+                ..Default::default()
+            };
+            assert!(matches!(
+                client
+                    .update_channel("nixos-25.05", &[&third], &bad_rev)
+                    .await,
+                Err(PersistentError::InvalidInput { .. })
+            ));
+        }
+
+        // Neither re-uploads nor wrong extensions are accepted.
+        assert!(matches!(
+            client
+                .update_channel("nixos-25.05", &[&second], &PublishInfo::default())
+                .await,
+            Err(PersistentError::AlreadyExists { .. })
+        ));
+        let iso = dir.file("nixos-25.05-3.iso");
+        assert!(matches!(
+            client
+                .update_channel("nixos-25.05", &[&iso], &PublishInfo::default())
+                .await,
+            Err(PersistentError::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            client
+                .update_channel("nixos-unstable", &[&iso], &PublishInfo::default())
+                .await,
+            Err(PersistentError::NotFound { .. })
+        ));
+
+        let channel = client
+            .load_channels_config()
+            .await
+            .unwrap()
+            .channel("nixos-25.05")
+            .unwrap();
+        assert_eq!(channel.latest_name(), Some("nixos-25.05-2"));
+        assert_eq!(previous_names(&channel), vec!["nixos-25.05-1"]);
+
+        let first_entry = &channel.previous[0];
+        assert!(first_entry.published.is_some());
+        assert_eq!(first_entry.uploader.as_deref(), Some("alice"));
+        assert_eq!(first_entry.size, Some(20));
+        assert_eq!(
+            first_entry.sha256.as_deref(),
+            Some(format!("{:x}", Sha256::digest("nixos-25.05-1.tar.xz")).as_str())
+        );
+        assert_eq!(first_entry.git_revision.as_deref(), Some(REV));
+        assert_eq!(first_entry.flake_query(), format!("?rev={REV}&revCount=42"));
+        assert_eq!(channel.latest.as_ref().unwrap().flake_query(), "");
+        assert_eq!(channel.latest.as_ref().unwrap().uploader, None);
+
+        assert_eq!(
+            client
+                .sign_request(http::Method::GET, "nixos-25.05-2.tar.xz", None)
+                .await
+                .unwrap(),
+            "memory:///nixos-25.05-2.tar.xz"
+        );
+
+        let object = client
+            .read_stream("nixos-25.05-2.tar.xz", None)
+            .await
+            .unwrap();
+        assert_eq!(object.content_length, Some(20));
+        assert_eq!(
+            axum::body::to_bytes(object.body, usize::MAX).await.unwrap(),
+            "nixos-25.05-2.tar.xz"
+        );
+        assert!(client
+            .sign_request(http::Method::POST, "nixos-25.05-2.tar.xz", None)
+            .await
+            .is_err());
+
+        // Even without its files, a release name can't be reused.
+        client
+            .storage
+            .delete(&client.key("nixos-25.05-1.tar.xz"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client
+                .update_channel("nixos-25.05", &[&first], &PublishInfo::default())
+                .await,
+            Err(PersistentError::AlreadyExists { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn releases_can_have_several_files() {
+        let client = Client::new(MemoryStorage::new());
+        client
+            .add_channel(
+                "nixos-25.05",
+                ".tar.xz",
+                &[".iso".to_owned(), ".xz".to_owned()],
+            )
+            .await
+            .unwrap();
+
+        let dir = TempDir::new("artifacts");
+        let tarball = dir.file("nixos-25.05-1.tar.xz");
+        let iso = dir.file("nixos-25.05-1.iso");
+        let other_iso = dir.file("nixos-25.05-2.iso");
+
+        for files in [
+            // The main file is missing.
+            vec![iso.as_path()],
+            // Names don't match.
+            vec![tarball.as_path(), other_iso.as_path()],
+            // Twice the same extension.
+            vec![tarball.as_path(), iso.as_path(), iso.as_path()],
+        ] {
+            assert!(matches!(
+                client
+                    .update_channel("nixos-25.05", &files, &PublishInfo::default())
+                    .await,
+                Err(PersistentError::InvalidInput { .. })
+            ));
+        }
+
+        client
+            .update_channel("nixos-25.05", &[&iso, &tarball], &PublishInfo::default())
+            .await
+            .unwrap();
+
+        let second = dir.file("nixos-25.05-2.tar.xz");
+        client
+            .update_channel("nixos-25.05", &[&second], &PublishInfo::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            client.storage.list("nixos-25.05-").await.unwrap(),
+            vec![
+                "nixos-25.05-1.iso",
+                "nixos-25.05-1.tar.xz",
+                "nixos-25.05-2.tar.xz"
+            ]
+        );
+
+        let config = client.load_channels_config().await.unwrap();
+        let channel = config.channel("nixos-25.05").unwrap();
+        let first = &channel.previous[0];
+        assert_eq!(first.size, Some(20));
+        assert_eq!(first.artifacts[".iso"].size, Some(17));
+        assert!(channel.contains_object("nixos-25.05-1.iso"));
+        assert!(!channel.contains_object("nixos-25.05-2.iso"));
+        assert!(!channel.contains_object("nixos-25.05-1.xz"));
+
+        let lookup = |file_name| {
+            config
+                .channel_for_file_name(file_name)
+                .map(|(_, _, index, file_extension)| (index, file_extension))
+        };
+        assert_eq!(lookup("nixos-25.05.tar.xz"), Some((0, ".tar.xz")));
+        assert_eq!(lookup("nixos-25.05~1.iso"), Some((1, ".iso")));
+        assert!(!channel.provides(channel.nth_latest(0).unwrap(), ".iso"));
+        assert!(channel.provides(channel.nth_latest(1).unwrap(), ".iso"));
+    }
+
+    #[tokio::test]
+    async fn extensions_can_be_added_later() {
+        let client = Client::new(MemoryStorage::new());
+        client
+            .add_channel("nixos-25.05", ".tar.xz", &[])
+            .await
+            .unwrap();
+
+        let dir = TempDir::new("extensions");
+        let tarball = dir.file("nixos-25.05-1.tar.xz");
+        let iso = dir.file("nixos-25.05-1.iso");
+        assert!(matches!(
+            client
+                .update_channel("nixos-25.05", &[&tarball, &iso], &PublishInfo::default())
+                .await,
+            Err(PersistentError::InvalidInput { .. })
+        ));
+
+        client
+            .add_file_extensions("nixos-25.05", &[".iso".to_owned(), ".tar.xz".to_owned()])
+            .await
+            .unwrap();
+        // Adding them again changes nothing.
+        client
+            .add_file_extensions("nixos-25.05", &[".iso".to_owned()])
+            .await
+            .unwrap();
+        assert!(matches!(
+            client
+                .add_file_extensions("nixos-25.05", &["".to_owned()])
+                .await,
+            Err(PersistentError::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            client
+                .add_file_extensions("nixos-unstable", &[".iso".to_owned()])
+                .await,
+            Err(PersistentError::NotFound { .. })
+        ));
+
+        client
+            .update_channel("nixos-25.05", &[&tarball, &iso], &PublishInfo::default())
+            .await
+            .unwrap();
+
+        let config = client.load_channels_config().await.unwrap();
+        let channel = config.channel("nixos-25.05").unwrap();
+        assert_eq!(channel.extra_file_extensions, vec![".iso"]);
+        assert!(channel.contains_object("nixos-25.05-1.iso"));
+    }
+
+    #[tokio::test]
+    async fn aliases_work() {
+        let client = Client::new(MemoryStorage::new());
+        client
+            .add_channel("nixos-25.05", ".tar.xz", &[])
+            .await
+            .unwrap();
+
+        client
+            .set_alias("stable", Some("nixos-25.05"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.set_alias("lts", Some("nixos-24.11")).await,
+            Err(PersistentError::NotFound { .. })
+        ));
+        assert!(matches!(
+            client.set_alias("nixos-25.05", Some("nixos-25.05")).await,
+            Err(PersistentError::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            client.set_alias("stable~1", Some("nixos-25.05")).await,
+            Err(PersistentError::InvalidInput { .. })
+        ));
+        assert!(matches!(
+            client.add_channel("stable", ".tar.xz", &[]).await,
+            Err(PersistentError::InvalidInput { .. })
+        ));
+
+        let config = client.load_channels_config().await.unwrap();
+        assert_eq!(
+            config.aliases().collect::<Vec<_>>(),
+            vec![("stable", "nixos-25.05")]
+        );
+        assert_eq!(config.resolve_alias("stable"), "nixos-25.05");
+        assert_eq!(config.resolve_alias("nixos-25.05"), "nixos-25.05");
+        assert!(config.channel("stable").is_none());
+
+        client.set_alias("stable", None).await.unwrap();
+        client.set_alias("stable", None).await.unwrap();
+        let config = client.load_channels_config().await.unwrap();
+        assert_eq!(config.aliases().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn prefix_is_applied_to_all_keys() {
+        let client = Client::new(MemoryStorage::new()).with_prefix("/trees/nixos/");
+
+        client
+            .add_channel("nixos-25.05", ".tar.xz", &[])
+            .await
+            .unwrap();
+        let dir = TempDir::new("prefix");
+        let file = dir.file("nixos-25.05-1.tar.xz");
+        client
+            .update_channel("nixos-25.05", &[&file], &PublishInfo::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            client.storage.list("").await.unwrap(),
+            vec![
+                "trees/nixos/channels.json",
+                "trees/nixos/nixos-25.05-1.tar.xz",
+                "trees/nixos/nixos-25.05.json"
+            ]
+        );
+        assert_eq!(
+            client
+                .load_channels_config()
+                .await
+                .unwrap()
+                .channel("nixos-25.05")
+                .unwrap()
+                .latest_name(),
+            Some("nixos-25.05-1")
+        );
+        assert_eq!(
+            client
+                .sign_request(http::Method::GET, "nixos-25.05-1.tar.xz", None)
+                .await
+                .unwrap(),
+            "memory:///trees/nixos/nixos-25.05-1.tar.xz"
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_updates_are_not_lost() {
+        let conflicts = Arc::new(AtomicUsize::new(0));
+        let client = Client::new(RacyStorage::new(conflicts.clone()));
+        client
+            .add_channel("nixos-25.05", ".tar.xz", &[])
+            .await
+            .unwrap();
+
+        // Let another publisher win twice. We must retry and keep their
+        // versions in the history.
+        conflicts.store(2, Ordering::SeqCst);
+        let dir = TempDir::new("racy");
+        let file = dir.file("nixos-25.05-1.tar.xz");
+        client
+            .update_channel("nixos-25.05", &[&file], &PublishInfo::default())
+            .await
+            .unwrap();
+
+        let channel = client
+            .load_channels_config()
+            .await
+            .unwrap()
+            .channel("nixos-25.05")
+            .unwrap();
+        assert_eq!(channel.latest_name(), Some("nixos-25.05-1"));
+        assert_eq!(
+            previous_names(&channel),
+            vec!["other-2".to_owned(), "other-1".to_owned()]
+        );
+
+        // If we keep losing, we give up instead of overwriting.
+        conflicts.store(UPDATE_ATTEMPTS, Ordering::SeqCst);
+        let file = dir.file("nixos-25.05-2.tar.xz");
+        assert!(matches!(
+            client
+                .update_channel("nixos-25.05", &[&file], &PublishInfo::default())
+                .await,
+            Err(PersistentError::Conflict { .. })
+        ));
+
+        let channel = client
+            .load_channels_config()
+            .await
+            .unwrap()
+            .channel("nixos-25.05")
+            .unwrap();
+        assert_ne!(channel.latest_name(), Some("nixos-25.05-2"));
+    }
+
+    #[tokio::test]
+    async fn concurrent_channel_additions_are_not_lost() {
+        let conflicts = Arc::new(AtomicUsize::new(0));
+        let client = Client::new(RacyStorage::new(conflicts.clone()));
+        client.add_channel("first", ".tar.xz", &[]).await.unwrap();
+
+        conflicts.store(2, Ordering::SeqCst);
+        client.add_channel("second", ".tar.xz", &[]).await.unwrap();
+
+        let persistent_config: PersistentChannelsConfig =
+            serde_json::from_slice(&client.storage.read("channels.json").await.unwrap()).unwrap();
+        assert_eq!(
+            persistent_config.channels,
+            vec!["first", "other-2", "other-1", "second"]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_channel_addition_is_rolled_back() {
+        let client = Client::new(RacyStorage {
+            broken: Some("channels.json"),
+            ..RacyStorage::new(Default::default())
+        });
+
+        assert!(client.add_channel("first", ".tar.xz", &[]).await.is_err());
+        assert!(!client.storage.exists("first.json").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn presign_expiry_is_limited() {
+        let storage = MemoryStorage::new();
+        storage
+            .write(
+                "channels.json",
+                Bytes::from(r#"{ "channels": ["short", "long", "zero"] }"#),
+            )
+            .await
+            .unwrap();
+        storage
+            .write(
+                "short.json",
+                Bytes::from(r#"{ "presign_expiry_seconds": 3600 }"#),
+            )
+            .await
+            .unwrap();
+        storage
+            .write(
+                "long.json",
+                Bytes::from(r#"{ "presign_expiry_seconds": 31536000 }"#),
+            )
+            .await
+            .unwrap();
+        storage
+            .write(
+                "zero.json",
+                Bytes::from(r#"{ "presign_expiry_seconds": 0 }"#),
+            )
+            .await
+            .unwrap();
+
+        let config = Client::new(storage).load_channels_config().await.unwrap();
+        assert_eq!(
+            config.channel("short").unwrap().presign_expiry(),
+            Some(Duration::from_secs(3600))
+        );
+        assert_eq!(
+            config.channel("long").unwrap().presign_expiry(),
+            Some(MAX_PRESIGN_EXPIRY)
+        );
+        assert_eq!(config.channel("zero").unwrap().presign_expiry(), None);
+    }
+
+    #[test]
+    fn channel_config_finds_objects() {
+        let channel: ChannelConfig = serde_json::from_str(
+            r#"{ "latest": "foo-2", "previous": ["foo-1"], "presign_expiry_seconds": 3600 }"#,
+        )
+        .unwrap();
+
+        assert_eq!(channel.presign_expiry(), Some(Duration::from_secs(3600)));
+        assert!(channel.contains_object("foo-2.tar.xz"));
+        assert!(channel.contains_object("foo-1.tar.xz"));
+        assert!(!channel.contains_object("foo-1.iso"));
+        assert!(!channel.contains_object("foo-3.tar.xz"));
+        assert!(!channel.contains_object("channels.json"));
+
+        let channels = ChannelsConfig {
+            channels: BTreeMap::from([("foo".to_owned(), channel)]),
+            ..Default::default()
+        };
+        assert_eq!(
+            channels
+                .channel_for_object("foo-1.tar.xz")
+                .map(|(name, _)| name),
+            Some("foo")
+        );
+        assert!(channels.channel_for_object("bar-1.tar.xz").is_none());
+    }
+
+    #[test]
+    fn channel_history_is_addressable() {
+        let channel: ChannelConfig =
+            serde_json::from_str(r#"{ "latest": "foo-3", "previous": ["foo-1", "foo-2"] }"#)
+                .unwrap();
+
+        assert_eq!(
+            channel.nth_latest(0).map(|entry| entry.name.as_str()),
+            Some("foo-3")
+        );
+        assert_eq!(
+            channel.nth_latest(1).map(|entry| entry.name.as_str()),
+            Some("foo-2")
+        );
+        assert_eq!(
+            channel.nth_latest(2).map(|entry| entry.name.as_str()),
+            Some("foo-1")
+        );
+        assert_eq!(channel.nth_latest(3).map(|entry| entry.name.as_str()), None);
+
+        let channels = ChannelsConfig {
+            channels: BTreeMap::from([
+                ("foo".to_owned(), channel.clone()),
+                ("foo-24.05".to_owned(), channel),
+            ]),
+            aliases: BTreeMap::from([
+                ("stable".to_owned(), "foo-24.05".to_owned()),
+                ("dangling".to_owned(), "bar".to_owned()),
+            ]),
+        };
+        let lookup = |file_name| {
+            channels
+                .channel_for_file_name(file_name)
+                .map(|(name, _, index, _)| (name, index))
+        };
+
+        assert_eq!(lookup("foo.tar.xz"), Some(("foo", 0)));
+        assert_eq!(lookup("foo~2.tar.xz"), Some(("foo", 2)));
+        assert_eq!(lookup("foo-24.05~1.tar.xz"), Some(("foo-24.05", 1)));
+        assert_eq!(lookup("foo-24.05.tar.xz"), Some(("foo-24.05", 0)));
+        assert_eq!(lookup("foo~.tar.xz"), None);
+        assert_eq!(lookup("foo~-1.tar.xz"), None);
+        assert_eq!(lookup("foo~+1.tar.xz"), None);
+        assert_eq!(lookup("foo.iso"), None);
+        assert_eq!(lookup("stable.tar.xz"), Some(("foo-24.05", 0)));
+        assert_eq!(lookup("stable~1.tar.xz"), Some(("foo-24.05", 1)));
+        assert_eq!(lookup("dangling.tar.xz"), None);
+    }
+
+    #[test]
+    fn channel_history_is_addressable_by_time() {
+        let channel: ChannelConfig = serde_json::from_str(
+            r#"{
+                "latest": { "name": "foo-3", "published": "2025-06-01T00:00:00Z" },
+                "previous": [
+                    "foo-1",
+                    { "name": "foo-2", "published": "2025-05-01T00:00:00Z" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let at = |timestamp| {
+            channel.index_at(
+                OffsetDateTime::parse(timestamp, &time::format_description::well_known::Rfc3339)
+                    .unwrap(),
+            )
+        };
+
+        assert_eq!(at("2025-07-01T00:00:00Z"), Some(0));
+        assert_eq!(at("2025-06-01T00:00:00Z"), Some(0));
+        assert_eq!(at("2025-05-31T23:59:59+02:00"), Some(1));
+        assert_eq!(at("2025-05-01T00:00:00Z"), Some(1));
+
+        // We don't know when foo-1 was published.
+        assert_eq!(at("2025-04-01T00:00:00Z"), None);
+
+        // Entries are written back with their publish time.
+        let json = serde_json::to_value(&channel).unwrap();
+        assert_eq!(json["latest"]["published"], "2025-06-01T00:00:00Z");
+        assert_eq!(json["previous"][0]["name"], "foo-1");
+    }
 
     #[test]
     fn remove_duplicates_works() {
         // Test with empty vec
         let mut vec: Vec<i32> = vec![];
-        assert!(!remove_duplicates(&mut vec));
+        assert!(!remove_duplicates_by_key(&mut vec, |item| *item));
         assert_eq!(vec, Vec::<i32>::new());
 
         // Test with duplicates
         let mut vec = vec![1, 2, 3, 2, 4, 3, 5];
-        assert!(remove_duplicates(&mut vec));
+        assert!(remove_duplicates_by_key(&mut vec, |item| *item));
         assert_eq!(vec, vec![1, 2, 3, 4, 5]);
 
         // Test with no duplicates
         let mut vec = vec![1, 2, 3, 4, 5];
-        assert!(!remove_duplicates(&mut vec));
+        assert!(!remove_duplicates_by_key(&mut vec, |item| *item));
         assert_eq!(vec, vec![1, 2, 3, 4, 5]);
 
         // Test with single element
         let mut vec = vec![1];
-        assert!(!remove_duplicates(&mut vec));
+        assert!(!remove_duplicates_by_key(&mut vec, |item| *item));
         assert_eq!(vec, vec![1]);
 
         // Test with all duplicates
         let mut vec = vec![1, 1, 1, 1];
-        assert!(remove_duplicates(&mut vec));
+        assert!(remove_duplicates_by_key(&mut vec, |item| *item));
         assert_eq!(vec, vec![1]);
 
         // Test with strings
@@ -422,12 +1857,12 @@ mod tests {
             "a".to_string(),
             "c".to_string(),
         ];
-        assert!(remove_duplicates(&mut vec));
+        assert!(remove_duplicates_by_key(&mut vec, String::clone));
         assert_eq!(vec, vec!["a".to_string(), "b".to_string(), "c".to_string()]);
 
         // Test that order is preserved
         let mut vec = vec![3, 1, 2, 1, 3, 2];
-        assert!(remove_duplicates(&mut vec));
+        assert!(remove_duplicates_by_key(&mut vec, |item| *item));
         assert_eq!(vec, vec![3, 1, 2]);
     }
 }
@@ -0,0 +1,162 @@
+//! Storage backends for channel metadata and tarballs.
+//!
+//! [`crate::persistent::Client`] only talks to the [`Storage`] trait. The
+//! object keys it uses are plain strings, such as `channels.json` or
+//! `nixos-25.05-2025-05-15.tar.xz`.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http,
+};
+
+use crate::error::{PersistentError, RequestError};
+
+pub mod local;
+pub mod memory;
+pub mod s3;
+
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+pub use s3::{S3Options, S3Storage};
+
+/// How a client gets the content of an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    /// The client should be redirected to this (presigned) URL.
+    Redirect(String),
+    /// We have to send this file to the client ourselves.
+    File(PathBuf),
+    /// We stream the object through the server with
+    /// [`Storage::read_stream`].
+    Proxy,
+}
+
+/// The content of an object that is streamed through the server.
+pub struct ObjectStream {
+    pub body: Body,
+    pub content_length: Option<u64>,
+    /// If the backend honored a range request, this is the value of the
+    /// Content-Range header.
+    pub content_range: Option<String>,
+    pub content_type: Option<String>,
+    pub e_tag: Option<String>,
+}
+
+/// A condition that must hold for a write to go through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Precondition {
+    /// The object must not exist yet.
+    Absent,
+    /// The object must still have this version, as returned by
+    /// [`Storage::read_versioned`].
+    Version(String),
+}
+
+/// The operations we need from a storage backend.
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+    /// Read an object into memory. This should only be used for small
+    /// objects.
+    async fn read(&self, object_key: &str) -> Result<Bytes, PersistentError>;
+
+    /// Read an object together with an opaque version identifier (the
+    /// ETag for S3). The version changes whenever the object is
+    /// written.
+    async fn read_versioned(&self, object_key: &str) -> Result<(Bytes, String), PersistentError>;
+
+    /// Open an object to stream its content to a client. `range` is the
+    /// value of the HTTP Range header of the client. Backends may ignore
+    /// it and return the whole object.
+    async fn read_stream(
+        &self,
+        object_key: &str,
+        _range: Option<&str>,
+    ) -> Result<ObjectStream, PersistentError> {
+        let data = self.read(object_key).await?;
+
+        Ok(ObjectStream {
+            content_length: Some(data.len() as u64),
+            content_range: None,
+            content_type: None,
+            e_tag: None,
+            body: Body::from(data),
+        })
+    }
+
+    /// Create or replace an object with the given content.
+    async fn write(&self, object_key: &str, data: Bytes) -> Result<(), PersistentError>;
+
+    /// Create or replace an object, but only if the precondition holds
+    /// at the time of the write. This is how we detect concurrent
+    /// modifications.
+    ///
+    /// Returns false, if the precondition did not hold and nothing was
+    /// written.
+    async fn write_if(
+        &self,
+        object_key: &str,
+        data: Bytes,
+        precondition: Precondition,
+    ) -> Result<bool, PersistentError>;
+
+    /// Create or replace an object with the content of a local file,
+    /// but only if the precondition holds. See [`Storage::write_if`].
+    async fn write_file_if(
+        &self,
+        object_key: &str,
+        file: &Path,
+        precondition: Precondition,
+    ) -> Result<bool, PersistentError> {
+        let data = tokio::fs::read(file)
+            .await
+            .map_err(|source| PersistentError::Io {
+                path: file.to_owned(),
+                source,
+            })?;
+
+        self.write_if(object_key, data.into(), precondition).await
+    }
+
+    /// Remove an object. Removing an object that doesn't exist is not an
+    /// error.
+    async fn delete(&self, object_key: &str) -> Result<(), PersistentError>;
+
+    /// Check whether an object exists.
+    async fn exists(&self, object_key: &str) -> Result<bool, PersistentError>;
+
+    /// List all object keys that start with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, PersistentError>;
+
+    /// Return a URL that allows a client to access the object without
+    /// further authentication.
+    async fn presign(
+        &self,
+        method: http::Method,
+        object_key: &str,
+        expires_in: Duration,
+    ) -> Result<String, RequestError>;
+
+    /// Decide how a client gets the content of an object. By default,
+    /// we redirect to a presigned URL or, if `proxy` is set, stream the
+    /// object without presigning anything.
+    async fn deliver(
+        &self,
+        method: http::Method,
+        object_key: &str,
+        expires_in: Duration,
+        proxy: bool,
+    ) -> Result<Delivery, RequestError> {
+        if proxy {
+            return Ok(Delivery::Proxy);
+        }
+
+        Ok(Delivery::Redirect(
+            self.presign(method, object_key, expires_in).await?,
+        ))
+    }
+}
@@ -0,0 +1,329 @@
+//! A storage backend that keeps all objects in a local directory.
+//!
+//! Object keys map directly to file names below the root directory.
+//! This is meant for sites without any S3 service.
+
+use std::{
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use axum::{body::Bytes, http};
+use sha2::{Digest, Sha256};
+
+use super::{Delivery, Precondition, Storage};
+use crate::error::{PersistentError, RequestError};
+
+/// The file we lock to serialize conditional writes. It lives in the
+/// root directory, but is not an object.
+const LOCK_FILE: &str = ".s3-nix-channel.lock";
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+/// Wrap an I/O error on a path that is not an object.
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> PersistentError + '_ {
+    move |source| PersistentError::Io {
+        path: path.to_owned(),
+        source,
+    }
+}
+
+impl LocalStorage {
+    /// Serve objects from the given directory. The directory must
+    /// exist.
+    pub fn new(root: &Path) -> Result<LocalStorage, PersistentError> {
+        if !root.is_dir() {
+            return Err(PersistentError::InvalidInput {
+                reason: format!("Not a directory: {}", root.display()),
+            });
+        }
+
+        Ok(Self {
+            root: root.to_owned(),
+        })
+    }
+
+    /// Map an object key to its path in the filesystem. We refuse
+    /// anything that could escape the root directory.
+    fn object_path(&self, object_key: &str) -> Option<PathBuf> {
+        let key = Path::new(object_key);
+
+        if object_key.is_empty()
+            || !key
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        Some(self.root.join(key))
+    }
+
+    fn checked_object_path(&self, object_key: &str) -> Result<PathBuf, PersistentError> {
+        self.object_path(object_key)
+            .ok_or_else(|| PersistentError::InvalidInput {
+                reason: format!("Invalid object key: {object_key:?}"),
+            })
+    }
+
+    /// Where we put the content of an object before it is moved into
+    /// place. Readers never see partially written objects this way.
+    fn temporary_path(path: &Path) -> PathBuf {
+        let mut file_name = path.file_name().unwrap_or_default().to_owned();
+        file_name.push(format!(".tmp-{}", std::process::id()));
+
+        path.with_file_name(file_name)
+    }
+
+    /// The version of an object is the hash of its content.
+    fn version(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    /// Take an exclusive lock on the whole directory. The lock is
+    /// released when the returned file is dropped.
+    async fn lock(&self) -> Result<std::fs::File, PersistentError> {
+        let lock_path = self.root.join(LOCK_FILE);
+
+        tokio::task::spawn_blocking(move || {
+            let lock_file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&lock_path)
+                .map_err(io_error(&lock_path))?;
+
+            lock_file.lock().map_err(io_error(&lock_path))?;
+
+            Ok(lock_file)
+        })
+        .await
+        .map_err(|err| PersistentError::Transport {
+            reason: format!("Locking task failed: {err}"),
+        })?
+    }
+
+    async fn create_parent_dir(path: &Path) -> Result<(), PersistentError> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(io_error(parent))?;
+        }
+
+        Ok(())
+    }
+
+    /// Check whether a precondition holds for an object. Callers must
+    /// hold the lock.
+    async fn precondition_holds(
+        &self,
+        object_key: &str,
+        precondition: &Precondition,
+    ) -> Result<bool, PersistentError> {
+        Ok(match precondition {
+            Precondition::Absent => !self.exists(object_key).await?,
+            Precondition::Version(version) => match self.read_versioned(object_key).await {
+                Ok((_, current_version)) => current_version == *version,
+                Err(PersistentError::NotFound { object_key: _ }) => false,
+                Err(err) => return Err(err),
+            },
+        })
+    }
+
+    /// Move a completely written temporary file into place.
+    async fn commit(
+        object_key: &str,
+        temporary_path: &Path,
+        path: &Path,
+    ) -> Result<(), PersistentError> {
+        tokio::fs::rename(temporary_path, path)
+            .await
+            .map_err(|source| PersistentError::from_io(object_key, path.to_owned(), source))
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for LocalStorage {
+    async fn read(&self, object_key: &str) -> Result<Bytes, PersistentError> {
+        let path = self.checked_object_path(object_key)?;
+
+        Ok(tokio::fs::read(&path)
+            .await
+            .map_err(|source| PersistentError::from_io(object_key, path, source))?
+            .into())
+    }
+
+    async fn read_versioned(&self, object_key: &str) -> Result<(Bytes, String), PersistentError> {
+        let data = self.read(object_key).await?;
+        let version = Self::version(&data);
+
+        Ok((data, version))
+    }
+
+    async fn write(&self, object_key: &str, data: Bytes) -> Result<(), PersistentError> {
+        let path = self.checked_object_path(object_key)?;
+        let temporary_path = Self::temporary_path(&path);
+
+        Self::create_parent_dir(&path).await?;
+        tokio::fs::write(&temporary_path, &data)
+            .await
+            .map_err(io_error(&temporary_path))?;
+
+        Self::commit(object_key, &temporary_path, &path).await
+    }
+
+    async fn write_if(
+        &self,
+        object_key: &str,
+        data: Bytes,
+        precondition: Precondition,
+    ) -> Result<bool, PersistentError> {
+        let _lock = self.lock().await?;
+
+        let holds = self.precondition_holds(object_key, &precondition).await?;
+        if holds {
+            self.write(object_key, data).await?;
+        }
+
+        Ok(holds)
+    }
+
+    /// The file is copied before we take the lock, so large files don't
+    /// block other writers.
+    async fn write_file_if(
+        &self,
+        object_key: &str,
+        file: &Path,
+        precondition: Precondition,
+    ) -> Result<bool, PersistentError> {
+        let path = self.checked_object_path(object_key)?;
+        let temporary_path = Self::temporary_path(&path);
+
+        Self::create_parent_dir(&path).await?;
+        tokio::fs::copy(file, &temporary_path)
+            .await
+            .map_err(io_error(file))?;
+
+        let _lock = self.lock().await?;
+
+        if !self.precondition_holds(object_key, &precondition).await? {
+            tokio::fs::remove_file(&temporary_path)
+                .await
+                .map_err(io_error(&temporary_path))?;
+            return Ok(false);
+        }
+
+        Self::commit(object_key, &temporary_path, &path).await?;
+        Ok(true)
+    }
+
+    async fn delete(&self, object_key: &str) -> Result<(), PersistentError> {
+        let path = self.checked_object_path(object_key)?;
+
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(PersistentError::from_io(object_key, path, err)),
+        }
+    }
+
+    async fn exists(&self, object_key: &str) -> Result<bool, PersistentError> {
+        let path = self.checked_object_path(object_key)?;
+
+        tokio::fs::try_exists(&path)
+            .await
+            .map_err(|source| PersistentError::from_io(object_key, path, source))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, PersistentError> {
+        let mut keys = Vec::new();
+        let mut directories = vec![self.root.clone()];
+
+        while let Some(directory) = directories.pop() {
+            let mut entries = tokio::fs::read_dir(&directory)
+                .await
+                .map_err(io_error(&directory))?;
+
+            while let Some(entry) = entries.next_entry().await.map_err(io_error(&directory))? {
+                let path = entry.path();
+
+                if entry.file_type().await.map_err(io_error(&path))?.is_dir() {
+                    directories.push(path);
+                    continue;
+                }
+
+                // Objects that are still being written are not visible.
+                let Some(key) = path
+                    .strip_prefix(&self.root)
+                    .ok()
+                    .and_then(Path::to_str)
+                    .filter(|key| !key.contains(".tmp-") && *key != LOCK_FILE)
+                else {
+                    continue;
+                };
+
+                if key.starts_with(prefix) {
+                    keys.push(key.to_owned());
+                }
+            }
+        }
+
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn presign(
+        &self,
+        _method: http::Method,
+        object_key: &str,
+        _expires_in: Duration,
+    ) -> Result<String, RequestError> {
+        // There is nobody else who could serve the file.
+        Err(RequestError::PresignFailure {
+            object_key: object_key.to_owned(),
+        })
+    }
+
+    async fn deliver(
+        &self,
+        _method: http::Method,
+        object_key: &str,
+        _expires_in: Duration,
+        _proxy: bool,
+    ) -> Result<Delivery, RequestError> {
+        self.object_path(object_key)
+            .map(Delivery::File)
+            .ok_or_else(|| RequestError::NoSuchObject {
+                object_key: object_key.to_owned(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_path_stays_in_root() {
+        let storage = LocalStorage {
+            root: PathBuf::from("/srv/channels"),
+        };
+
+        assert_eq!(
+            storage.object_path("channels.json"),
+            Some(PathBuf::from("/srv/channels/channels.json"))
+        );
+        assert_eq!(
+            storage.object_path("some/dir/foo.tar.xz"),
+            Some(PathBuf::from("/srv/channels/some/dir/foo.tar.xz"))
+        );
+
+        assert_eq!(storage.object_path(""), None);
+        assert_eq!(storage.object_path("/etc/passwd"), None);
+        assert_eq!(storage.object_path("../etc/passwd"), None);
+        assert_eq!(storage.object_path("foo/../../etc/passwd"), None);
+        assert_eq!(storage.object_path("./foo.tar.xz"), None);
+    }
+}
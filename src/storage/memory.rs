@@ -0,0 +1,130 @@
+//! An in-memory storage backend. This is mostly useful for tests.
+
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use axum::{
+    body::Bytes,
+    http::{self, Method},
+};
+
+use super::{Precondition, Storage};
+use crate::error::{PersistentError, RequestError};
+
+#[derive(Debug, Default)]
+struct Objects {
+    /// Each object with the version it was written with.
+    objects: BTreeMap<String, (Bytes, u64)>,
+    /// The version of the last write.
+    last_version: u64,
+}
+
+impl Objects {
+    fn insert(&mut self, object_key: &str, data: Bytes) {
+        self.last_version += 1;
+        self.objects
+            .insert(object_key.to_owned(), (data, self.last_version));
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: Mutex<Objects>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for MemoryStorage {
+    async fn read(&self, object_key: &str) -> Result<Bytes, PersistentError> {
+        Ok(self.read_versioned(object_key).await?.0)
+    }
+
+    async fn read_versioned(&self, object_key: &str) -> Result<(Bytes, String), PersistentError> {
+        self.objects
+            .lock()
+            .unwrap()
+            .objects
+            .get(object_key)
+            .map(|(data, version)| (data.clone(), version.to_string()))
+            .ok_or_else(|| PersistentError::NotFound {
+                object_key: object_key.to_owned(),
+            })
+    }
+
+    async fn write(&self, object_key: &str, data: Bytes) -> Result<(), PersistentError> {
+        self.objects.lock().unwrap().insert(object_key, data);
+
+        Ok(())
+    }
+
+    async fn write_if(
+        &self,
+        object_key: &str,
+        data: Bytes,
+        precondition: Precondition,
+    ) -> Result<bool, PersistentError> {
+        let mut objects = self.objects.lock().unwrap();
+        let current_version = objects
+            .objects
+            .get(object_key)
+            .map(|(_, version)| version.to_string());
+
+        let holds = match precondition {
+            Precondition::Absent => current_version.is_none(),
+            Precondition::Version(version) => current_version == Some(version),
+        };
+
+        if holds {
+            objects.insert(object_key, data);
+        }
+
+        Ok(holds)
+    }
+
+    async fn delete(&self, object_key: &str) -> Result<(), PersistentError> {
+        self.objects.lock().unwrap().objects.remove(object_key);
+
+        Ok(())
+    }
+
+    async fn exists(&self, object_key: &str) -> Result<bool, PersistentError> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .objects
+            .contains_key(object_key))
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, PersistentError> {
+        Ok(self
+            .objects
+            .lock()
+            .unwrap()
+            .objects
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    /// There is nothing to sign, so we hand out `memory:` URLs that
+    /// only identify the object.
+    async fn presign(
+        &self,
+        method: http::Method,
+        object_key: &str,
+        _expires_in: Duration,
+    ) -> Result<String, RequestError> {
+        match method {
+            Method::GET | Method::HEAD => Ok(format!("memory:///{object_key}")),
+            unsupported => Err(RequestError::UnsupportedMethod {
+                method: unsupported,
+            }),
+        }
+    }
+}
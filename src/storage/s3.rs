@@ -0,0 +1,625 @@
+//! The S3 storage backend.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use aws_config::{BehaviorVersion, Region};
+use aws_runtime::env_config::file::{EnvConfigFileKind, EnvConfigFiles};
+
+use aws_sdk_s3::{
+    config::http::HttpResponse,
+    error::{DisplayErrorContext, SdkError},
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use axum::{
+    body::{Body, Bytes},
+    http::{self, Method},
+};
+use serde::Deserialize;
+use tokio::{fs::File, io::AsyncReadExt, task::JoinSet};
+use tokio_util::io::ReaderStream;
+use tracing::error;
+
+use super::{ObjectStream, Precondition, Storage};
+use crate::error::{PersistentError, RequestError};
+
+/// The size of each part of a multipart upload. S3 requires at least
+/// 5 MiB and allows at most 10000 parts.
+const MULTIPART_PART_SIZE: usize = 16 * 1024 * 1024;
+
+/// How many parts of a multipart upload are uploaded in parallel.
+const MULTIPART_CONCURRENCY: usize = 4;
+
+/// How buckets are addressed in requests.
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressingStyle {
+    /// https://endpoint/bucket/key. This is what minio and most other
+    /// S3-compatible providers expect.
+    Path,
+    /// https://bucket.endpoint/key
+    Virtual,
+}
+
+/// How to connect to S3. Everything that is not set here is taken from
+/// the usual AWS environment variables and configuration files.
+///
+/// The options can be given on the command line or in a JSON
+/// configuration file. The command line takes precedence.
+#[derive(clap::Args, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct S3Options {
+    /// A JSON file with S3 connection options. The keys are the same
+    /// as the command line options without the s3- prefix and with
+    /// underscores, e.g. "endpoint_url".
+    #[arg(long = "s3-config", global = true)]
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+
+    /// The S3 endpoint URL, e.g. https://nbg1.your-objectstorage.com.
+    #[arg(long = "s3-endpoint-url", global = true)]
+    pub endpoint_url: Option<String>,
+
+    /// The S3 region, e.g. eu-central-1.
+    #[arg(long = "s3-region", global = true)]
+    pub region: Option<String>,
+
+    /// How buckets are addressed. Defaults to path-style addressing.
+    #[arg(long = "s3-addressing-style", global = true, value_enum)]
+    pub addressing_style: Option<AddressingStyle>,
+
+    /// The profile to take credentials and configuration from.
+    #[arg(long = "s3-profile", global = true)]
+    pub profile: Option<String>,
+
+    /// A credentials file to use instead of ~/.aws/credentials.
+    #[arg(long = "s3-credentials-file", global = true)]
+    pub credentials_file: Option<PathBuf>,
+}
+
+impl S3Options {
+    /// Fill in everything that was not given on the command line from
+    /// the configuration file, if there is one.
+    pub fn resolve(self) -> Result<S3Options, PersistentError> {
+        let Some(config_file) = &self.config_file else {
+            return Ok(self);
+        };
+
+        let data = std::fs::read(config_file).map_err(|source| PersistentError::Io {
+            path: config_file.to_owned(),
+            source,
+        })?;
+        let from_file: S3Options =
+            serde_json::from_slice(&data).map_err(|source| PersistentError::Deserialization {
+                object_key: config_file.display().to_string(),
+                source,
+            })?;
+
+        Ok(S3Options {
+            config_file: self.config_file,
+            endpoint_url: self.endpoint_url.or(from_file.endpoint_url),
+            region: self.region.or(from_file.region),
+            addressing_style: self.addressing_style.or(from_file.addressing_style),
+            profile: self.profile.or(from_file.profile),
+            credentials_file: self.credentials_file.or(from_file.credentials_file),
+        })
+    }
+}
+
+pub struct S3Storage {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    /// Open an S3 client with configuration from the environment.
+    pub async fn new_from_env(bucket: &str) -> Result<S3Storage, PersistentError> {
+        Self::new(bucket, S3Options::default()).await
+    }
+
+    /// Open an S3 client. Explicit options override the configuration
+    /// from the environment.
+    pub async fn new(bucket: &str, options: S3Options) -> Result<S3Storage, PersistentError> {
+        let options = options.resolve()?;
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+
+        if let Some(endpoint_url) = options.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url);
+        }
+
+        if let Some(region) = options.region {
+            loader = loader.region(Region::new(region));
+        }
+
+        if let Some(profile) = options.profile {
+            loader = loader.profile_name(profile);
+        }
+
+        if let Some(credentials_file) = options.credentials_file {
+            loader = loader.profile_files(
+                EnvConfigFiles::builder()
+                    .include_default_config_file(true)
+                    .with_file(EnvConfigFileKind::Credentials, credentials_file)
+                    .build(),
+            );
+        }
+
+        let amzn_config = loader.load().await;
+        let s3_config = aws_sdk_s3::config::Builder::from(&amzn_config)
+            .force_path_style(
+                options.addressing_style.unwrap_or(AddressingStyle::Path) == AddressingStyle::Path,
+            )
+            .build();
+
+        Ok(Self {
+            client: aws_sdk_s3::Client::from_conf(s3_config),
+            bucket: bucket.to_owned(),
+        })
+    }
+}
+
+impl S3Storage {
+    /// Upload all parts of a multipart upload and complete it, if the
+    /// precondition holds. The first part has already been read by the
+    /// caller from `input`, which was opened from `file`.
+    async fn upload_parts(
+        &self,
+        object_key: &str,
+        upload_id: &str,
+        first_part: Bytes,
+        input: &mut File,
+        file: &Path,
+        precondition: Precondition,
+    ) -> Result<bool, PersistentError> {
+        let mut uploads = JoinSet::new();
+        let mut completed_parts = Vec::new();
+
+        let mut part = first_part;
+        let mut part_number = 1;
+
+        while !part.is_empty() {
+            // Limit the number of parts in flight to bound memory usage.
+            if uploads.len() >= MULTIPART_CONCURRENCY {
+                if let Some(completed) = uploads.join_next().await {
+                    completed_parts.push(completed.map_err(task_failure)??);
+                }
+            }
+
+            let key = object_key.to_owned();
+            let request = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(object_key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(part));
+
+            uploads.spawn(async move {
+                let response = request.send().await.map_err(|err| classify(&key, err))?;
+
+                Ok::<_, PersistentError>(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(response.e_tag().map(str::to_owned))
+                        .build(),
+                )
+            });
+
+            part = read_part(input, file).await?;
+            part_number += 1;
+        }
+
+        while let Some(completed) = uploads.join_next().await {
+            completed_parts.push(completed.map_err(task_failure)??);
+        }
+
+        completed_parts.sort_by_key(|part| part.part_number());
+
+        let request = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(completed_parts))
+                    .build(),
+            );
+
+        let request = match precondition {
+            Precondition::Absent => request.if_none_match("*"),
+            Precondition::Version(etag) => request.if_match(etag),
+        };
+
+        match request
+            .send()
+            .await
+            .map_err(|err| classify(object_key, err))
+        {
+            Ok(_) => Ok(true),
+            Err(PersistentError::Conflict { object_key: _ }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Classify an error of the S3 SDK by the HTTP status of the response.
+///
+/// S3 answers conditional requests that failed with 412. It answers with
+/// 409, if a conflicting conditional write is in flight. Range requests
+/// beyond the end of the object fail with 416.
+fn classify<E>(object_key: &str, err: SdkError<E, HttpResponse>) -> PersistentError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let object_key = object_key.to_owned();
+
+    match err
+        .raw_response()
+        .map(|response| response.status().as_u16())
+    {
+        Some(404) => PersistentError::NotFound { object_key },
+        Some(416) => PersistentError::RangeNotSatisfiable {
+            object_key,
+            content_range: err
+                .raw_response()
+                .and_then(|response| response.headers().get("content-range"))
+                .map(str::to_owned),
+        },
+        Some(403) => PersistentError::PermissionDenied { object_key },
+        Some(409 | 412) => PersistentError::Conflict { object_key },
+        _ => PersistentError::Transport {
+            reason: format!("{object_key}: {}", DisplayErrorContext(&err)),
+        },
+    }
+}
+
+fn task_failure(err: tokio::task::JoinError) -> PersistentError {
+    PersistentError::Transport {
+        reason: format!("Part upload task failed: {err}"),
+    }
+}
+
+/// Read the next part of a multipart upload. Returns an empty buffer at
+/// the end of the file.
+async fn read_part(input: &mut File, file: &Path) -> Result<Bytes, PersistentError> {
+    let mut part = Vec::with_capacity(MULTIPART_PART_SIZE);
+
+    input
+        .take(MULTIPART_PART_SIZE as u64)
+        .read_to_end(&mut part)
+        .await
+        .map_err(|source| PersistentError::Io {
+            path: file.to_owned(),
+            source,
+        })?;
+
+    Ok(part.into())
+}
+
+/// Collect the body of a response into memory.
+async fn collect(object_key: &str, body: ByteStream) -> Result<Bytes, PersistentError> {
+    Ok(body
+        .collect()
+        .await
+        .map_err(|err| PersistentError::Transport {
+            reason: format!("{object_key}: {err}"),
+        })?
+        .into_bytes())
+}
+
+#[async_trait::async_trait]
+impl Storage for S3Storage {
+    async fn read(&self, object_key: &str) -> Result<Bytes, PersistentError> {
+        let response = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(|err| classify(object_key, err))?;
+
+        collect(object_key, response.body).await
+    }
+
+    async fn read_versioned(&self, object_key: &str) -> Result<(Bytes, String), PersistentError> {
+        let response = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(|err| classify(object_key, err))?;
+
+        let version = response
+            .e_tag()
+            .ok_or_else(|| PersistentError::Transport {
+                reason: format!("Object has no ETag: {object_key}"),
+            })?
+            .to_owned();
+
+        Ok((collect(object_key, response.body).await?, version))
+    }
+
+    async fn read_stream(
+        &self,
+        object_key: &str,
+        range: Option<&str>,
+    ) -> Result<ObjectStream, PersistentError> {
+        let response = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .set_range(range.map(str::to_owned))
+            .send()
+            .await
+            .map_err(|err| classify(object_key, err))?;
+
+        Ok(ObjectStream {
+            content_length: response
+                .content_length()
+                .and_then(|length| u64::try_from(length).ok()),
+            content_range: response.content_range().map(str::to_owned),
+            content_type: response.content_type().map(str::to_owned),
+            e_tag: response.e_tag().map(str::to_owned),
+            body: Body::from_stream(ReaderStream::new(response.body.into_async_read())),
+        })
+    }
+
+    async fn write(&self, object_key: &str, data: Bytes) -> Result<(), PersistentError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .map_err(|err| classify(object_key, err))?;
+
+        Ok(())
+    }
+
+    async fn write_if(
+        &self,
+        object_key: &str,
+        data: Bytes,
+        precondition: Precondition,
+    ) -> Result<bool, PersistentError> {
+        let request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .body(ByteStream::from(data));
+
+        let request = match precondition {
+            Precondition::Absent => request.if_none_match("*"),
+            Precondition::Version(etag) => request.if_match(etag),
+        };
+
+        match request
+            .send()
+            .await
+            .map_err(|err| classify(object_key, err))
+        {
+            Ok(_) => Ok(true),
+            Err(PersistentError::Conflict { object_key: _ }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Upload a file via multipart upload. Only a few parts are kept in
+    /// memory at any time, so this works for files of any size. The
+    /// precondition is checked when the upload is completed.
+    async fn write_file_if(
+        &self,
+        object_key: &str,
+        file: &Path,
+        precondition: Precondition,
+    ) -> Result<bool, PersistentError> {
+        let mut input = File::open(file)
+            .await
+            .map_err(|source| PersistentError::Io {
+                path: file.to_owned(),
+                source,
+            })?;
+
+        // Small files don't need the multipart dance.
+        let first_part = read_part(&mut input, file).await?;
+        if first_part.len() < MULTIPART_PART_SIZE {
+            return self.write_if(object_key, first_part, precondition).await;
+        }
+
+        let upload_id = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(|err| classify(object_key, err))?
+            .upload_id()
+            .ok_or_else(|| PersistentError::Transport {
+                reason: format!("Multipart upload without upload ID: {object_key}"),
+            })?
+            .to_owned();
+
+        let result = self
+            .upload_parts(
+                object_key,
+                &upload_id,
+                first_part,
+                &mut input,
+                file,
+                precondition,
+            )
+            .await;
+
+        if !matches!(result, Ok(true)) {
+            // Don't leave incomplete uploads behind. They are invisible,
+            // but still take up space.
+            if let Err(err) = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(object_key)
+                .upload_id(&upload_id)
+                .send()
+                .await
+            {
+                error!("Failed to abort multipart upload {upload_id} for {object_key}: {err}");
+            }
+        }
+
+        result
+    }
+
+    async fn delete(&self, object_key: &str) -> Result<(), PersistentError> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(|err| classify(object_key, err))?;
+
+        Ok(())
+    }
+
+    async fn exists(&self, object_key: &str) -> Result<bool, PersistentError> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(|err| classify(object_key, err))
+        {
+            Ok(_) => Ok(true),
+            Err(PersistentError::NotFound { object_key: _ }) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, PersistentError> {
+        let mut keys = Vec::new();
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(prefix)
+            .into_paginator()
+            .send();
+
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(|err| classify(prefix, err))?;
+            keys.extend(
+                page.contents()
+                    .iter()
+                    .filter_map(|object| object.key().map(str::to_owned)),
+            );
+        }
+
+        Ok(keys)
+    }
+
+    async fn presign(
+        &self,
+        method: http::Method,
+        object_key: &str,
+        expires_in: Duration,
+    ) -> Result<String, RequestError> {
+        use aws_sdk_s3::presigning::PresigningConfig;
+
+        let presigning_config = PresigningConfig::expires_in(expires_in)
+            .map_err(|_e| RequestError::PresignConfigFailure)?;
+
+        let req = match method {
+            Method::GET => self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(object_key)
+                .presigned(presigning_config)
+                .await
+                .map_err(|_e| RequestError::PresignFailure {
+                    object_key: object_key.to_owned(),
+                }),
+            Method::HEAD => self
+                .client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(object_key)
+                .presigned(presigning_config)
+                .await
+                .map_err(|_e| RequestError::PresignFailure {
+                    object_key: object_key.to_owned(),
+                }),
+            unsupported => Err(RequestError::UnsupportedMethod {
+                method: unsupported,
+            }),
+        }?;
+
+        Ok(req.uri().to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        /// Removes the config file again, even if the test fails.
+        struct Remove(std::path::PathBuf);
+
+        impl Drop for Remove {
+            fn drop(&mut self) {
+                let _ = std::fs::remove_file(&self.0);
+            }
+        }
+
+        let config_file = std::env::temp_dir().join(format!(
+            "s3-nix-channel-s3-options-{}.json",
+            std::process::id()
+        ));
+        let _remove = Remove(config_file.clone());
+        std::fs::write(
+            &config_file,
+            r#"{ "endpoint_url": "http://s3:9000", "region": "eu-central-1", "addressing_style": "virtual" }"#,
+        )
+        .unwrap();
+
+        let options = S3Options {
+            config_file: Some(config_file.clone()),
+            region: Some("us-east-1".to_owned()),
+            ..Default::default()
+        }
+        .resolve()
+        .unwrap();
+
+        assert_eq!(options.endpoint_url.as_deref(), Some("http://s3:9000"));
+        assert_eq!(options.region.as_deref(), Some("us-east-1"));
+        assert_eq!(options.addressing_style, Some(AddressingStyle::Virtual));
+        assert_eq!(options.profile, None);
+
+        std::fs::write(&config_file, r#"{ "bogus": 1 }"#).unwrap();
+        assert!(matches!(
+            S3Options {
+                config_file: Some(config_file),
+                ..Default::default()
+            }
+            .resolve(),
+            Err(PersistentError::Deserialization { .. })
+        ));
+    }
+}